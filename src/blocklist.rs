@@ -0,0 +1,459 @@
+//! Blocking names from hosts, plain and adblock style lists, with allowlist exceptions.
+
+use crate::dns::*;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+/// Host names that hosts files map for the machine itself, never worth blocking.
+const HOSTS_BUILTINS: [&str; 6] =
+    ["localhost", "localhost.localdomain", "local", "broadcasthost", "ip6-localhost", "0.0.0.0"];
+
+/// Where a blocklist or allowlist entry came from.
+#[derive(Debug, Clone)]
+pub struct Source {
+    /// File the entry was read from.
+    pub path: Arc<str>,
+    /// 1-based line number within `path`.
+    pub line: usize,
+    /// The line as written, without comments.
+    pub rule: String,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{} `{}`", self.path, self.line, self.rule)
+    }
+}
+
+/// A node of the suffix trie, children are keyed by lower cased label.
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Box<[u8]>, Node>,
+    /// The name ending at this node matches.
+    exact: Option<Source>,
+    /// The name ending at this node and everything below it matches.
+    subtree: Option<Source>,
+}
+
+/// Set of domains stored in a trie of labels walked from the top level domain down.
+#[derive(Debug, Default)]
+struct DomainSet {
+    root: Node,
+}
+
+impl DomainSet {
+    /// Adds `name`, and with `subtree` everything below it too.
+    fn insert(&mut self, name: &DomainName, subtree: bool, source: Source) {
+        let node = name.labels()
+                       .rev()
+                       .fold(&mut self.root, |node, label| {
+                           node.children
+                               .entry(label.to_ascii_lowercase().into_boxed_slice())
+                               .or_default()
+                       });
+
+        match subtree {
+            true => node.subtree.get_or_insert(source),
+            false => node.exact.get_or_insert(source),
+        };
+    }
+
+    /// Returns the most specific entry matching `name`.
+    fn find(&self, name: &DomainName) -> Option<&Source> {
+        let mut node = &self.root;
+        let mut found = None;
+
+        for label in name.labels().rev() {
+            found = node.subtree.as_ref().or(found);
+
+            match node.children.get(label.to_ascii_lowercase().as_slice()) {
+                Some(child) => node = child,
+                None => return found,
+            }
+        }
+
+        node.exact.as_ref().or(node.subtree.as_ref()).or(found)
+    }
+}
+
+/// Outcome for a name matched by a blocklist.
+#[derive(Debug)]
+pub enum Verdict<'a> {
+    /// Blocked by the given entry.
+    Blocked(&'a Source),
+    /// Blocked by one entry but let through by an allowlist entry.
+    Allowed {
+        /// The entry that blocks the name.
+        block: &'a Source,
+        /// The allowlist entry that lets it through.
+        allow: &'a Source,
+    },
+}
+
+/// Blocked domains, together with the allowed ones that override them.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    blocked: DomainSet,
+    allowed: DomainSet,
+}
+
+impl Blocklist {
+    /// Decides on `name`, `None` when no blocklist matches it.
+    pub fn check(&self, name: &DomainName) -> Option<Verdict<'_>> {
+        let block = self.blocked.find(name)?;
+
+        Some(match self.allowed.find(name) {
+            Some(allow) => Verdict::Allowed { block, allow },
+            None => Verdict::Blocked(block),
+        })
+    }
+
+    /// Reads a list file and adds its entries, returning how many were added.
+    ///
+    /// Entries of an allowlist override blocks instead of adding them.
+    pub fn load(&mut self, path: &str, allow: bool) -> Result<usize, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read list {}: {}", path, e))?;
+
+        let path: Arc<str> = Arc::from(path);
+        let mut count = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+
+            for (name, subtree, exception) in parse_line(line) {
+                let source = Source { path: Arc::clone(&path), line: i + 1, rule: line.to_string() };
+
+                match allow || exception {
+                    true => self.allowed.insert(&name, subtree, source),
+                    false => self.blocked.insert(&name, subtree, source),
+                }
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/// Cuts a hosts file or plain list comment off `line`, a `#` only starts one at the beginning
+/// of the line or after whitespace so adblock `##` element hiding rules stay whole.
+fn strip_comment(line: &str) -> &str {
+    let start = line.char_indices().find(|&(i, c)| {
+        c == '#' && line[..i].chars().next_back().is_none_or(char::is_whitespace)
+    });
+
+    match start {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+/// Whether `name` is made of nothing but the characters of host names, so paths and adblock
+/// syntax aren't taken for domains.
+fn is_host_name(name: &str) -> bool {
+    !name.is_empty() 
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Parses one line in hosts file, plain domain or Adblock format into its entries, as
+/// `(name, subtree, exception)`.
+///
+/// Hosts file and plain entries match just the name, Adblock `||name^` entries match the
+/// name and everything below it and `@@||name^` entries are exceptions to blocks. Comments,
+/// other adblock rules and lines we can't make sense of yield nothing.
+fn parse_line(line: &str) -> Vec<(DomainName, bool, bool)> {
+    // adblock comments and section headers.
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return Vec::new();
+    }
+
+    // element hiding, scriptlets, options, paths and wildcards only make sense to browsers,
+    // taking the domain in front of them would block all of it.
+    if ["##", "#@#", "#?#", "#$#", "$", "/", "*"].iter().any(|s| line.contains(s)) {
+        return Vec::new();
+    }
+
+    let (rule, exception) = match line.strip_prefix("@@") {
+        Some(rule) => (rule, true),
+        None => (line, false),
+    };
+
+    if let Some(rule) = rule.strip_prefix("||") {
+        // anything past the `^` is syntax we don't handle.
+        return rule.strip_suffix('^')
+                   .filter(|n| is_host_name(n))
+                   .and_then(|n| n.parse().ok())
+                   .map(|name| vec![(name, true, exception)])
+                   .unwrap_or_default();
+    }
+
+    // exceptions only exist as `@@||name^`.
+    if exception {
+        return Vec::new();
+    }
+
+    let mut words = line.split_whitespace();
+    let first = words.next().unwrap_or_default();
+
+    // hosts files start with the address the names map to.
+    let names: Vec<&str> = match first.parse::<IpAddr>() {
+        Ok(_) => words.filter(|n| !HOSTS_BUILTINS.contains(n)).collect(),
+        Err(_) => vec![first],
+    };
+
+    names.into_iter()
+         .filter(|name| is_host_name(name))
+         .filter_map(|name| name.parse().ok())
+         .map(|name| (name, false, false))
+         .collect()
+}
+
+/// What a blocked query is answered with.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum BlockResponse {
+    /// `0.0.0.0` for A and `::` for AAAA queries, no records for other types.
+    #[default]
+    Null,
+    /// A name error, as if the name didn't exist.
+    NxDomain,
+    /// The given addresses for A and AAAA queries, no records for other types.
+    Sinkhole {
+        /// Addresses of A answers.
+        v4: Vec<Ipv4Addr>,
+        /// Addresses of AAAA answers.
+        v6: Vec<Ipv6Addr>,
+    },
+}
+
+impl BlockResponse {
+    /// Builds the response to a blocked query, records living for `ttl` seconds.
+    pub fn respond(&self, query: &DNSPacket, ttl: u32) -> DNSPacket {
+        let (v4, v6): (&[Ipv4Addr], &[Ipv6Addr]) = match self {
+            BlockResponse::Null => (&[Ipv4Addr::UNSPECIFIED], &[Ipv6Addr::UNSPECIFIED]),
+            BlockResponse::NxDomain => {
+                let mut response = query.reply();
+                response.set_rcode(rcode::NXDOMAIN);
+                return response;
+            },
+            BlockResponse::Sinkhole { v4, v6 } => (v4, v6),
+        };
+
+        match query.questions.first().map(|q| q.ty) {
+            Some(record_type::A) => query.synthesize(v4.iter().map(|&a| RData::A(a)), ttl),
+            Some(record_type::AAAA) => query.synthesize(v6.iter().map(|&a| RData::Aaaa(a)), ttl),
+            _ => query.reply(),
+        }
+    }
+}
+
+/// Parses `null`, `nxdomain` or sinkhole addresses as `<address>[,<address>...]`.
+impl std::str::FromStr for BlockResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "null" => return Ok(BlockResponse::Null),
+            "nxdomain" => return Ok(BlockResponse::NxDomain),
+            _ => {},
+        }
+
+        let (v4, v6) = parse_addresses(s, "sinkhole")?;
+        Ok(BlockResponse::Sinkhole { v4, v6 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::MessageBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    /// Entries of a line as `load` reads them, with the names as text.
+    fn entries(line: &str) -> Vec<(String, bool, bool)> {
+        parse_line(strip_comment(line).trim())
+            .into_iter()
+            .map(|(name, subtree, exception)| (name.to_string(), subtree, exception))
+            .collect()
+    }
+
+    /// Loads `lists` in order into a blocklist, each as `(text, allow)`.
+    fn blocklist(lists: &[(&str, bool)]) -> Blocklist {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let mut blocklist = Blocklist::default();
+
+        for (text, allow) in lists {
+            let path = std::env::temp_dir().join(format!("maldns-list-{}-{}",
+                std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+            std::fs::write(&path, text).unwrap();
+
+            blocklist.load(path.to_str().unwrap(), *allow).unwrap();
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        blocklist
+    }
+
+    /// `Some(true)` if `s` is blocked, `Some(false)` if it's allowed over a block.
+    fn blocked(blocklist: &Blocklist, s: &str) -> Option<bool> {
+        blocklist.check(&name(s)).map(|verdict| matches!(verdict, Verdict::Blocked(_)))
+    }
+
+    fn exact(s: &str) -> (String, bool, bool) {
+        (s.to_string(), false, false)
+    }
+
+    #[test]
+    fn hosts_lines() {
+        assert_eq!(entries("0.0.0.0 ads.example"), [exact("ads.example")]);
+        assert_eq!(entries("127.0.0.1\tads.example  tracker.example"),
+                   [exact("ads.example"), exact("tracker.example")]);
+        assert_eq!(entries(":: ads.example"), [exact("ads.example")]);
+
+        for line in ["127.0.0.1 localhost", "::1 ip6-localhost", "0.0.0.0 0.0.0.0",
+                     "255.255.255.255 broadcasthost", "127.0.0.1 localhost.localdomain local"] {
+            assert!(entries(line).is_empty(), "{}", line);
+        }
+
+        assert_eq!(entries("127.0.0.1 localhost ads.example"), [exact("ads.example")]);
+    }
+
+    #[test]
+    fn plain_domains_and_comments() {
+        assert_eq!(entries("ads.example"), [exact("ads.example")]);
+        assert_eq!(entries("  ads.example  "), [exact("ads.example")]);
+        assert_eq!(entries("ads.example # tracking"), [exact("ads.example")]);
+        assert_eq!(entries("0.0.0.0 ads.example #tracking"), [exact("ads.example")]);
+        assert_eq!(entries("||ads.example^ # tracking"),
+                   [("ads.example".to_string(), true, false)]);
+
+        for line in ["", "# ads.example", "   # ads.example", "ads.example/banner",
+                     "http://ads.example", "ads.example:8080"] {
+            assert!(entries(line).is_empty(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn adblock_rules() {
+        assert_eq!(entries("||ads.example^"), [("ads.example".to_string(), true, false)]);
+        assert_eq!(entries("@@||good.example^"), [("good.example".to_string(), true, true)]);
+
+        // only whole domains are taken, everything a browser would need to apply is skipped.
+        for line in ["! Title: list", "[Adblock Plus 2.0]", "example.com##.banner",
+                     "example.com#@#.banner", "example.com#?#div:has(.ad)",
+                     "example.com#$#abort-on-property-read ads", "||ads.example^$third-party",
+                     "||ads.example/banner^", "||ads.example^|", "||*.ads.example^",
+                     "/banner/*", "@@good.example", "@@||good.example^$document", "||^"] {
+            assert!(entries(line).is_empty(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn domain_set_matches() {
+        let source = || Source { path: Arc::from("list"), line: 1, rule: String::new() };
+        let mut set = DomainSet::default();
+
+        set.insert(&name("exact.example"), false, source());
+        set.insert(&name("Tree.Example"), true, source());
+
+        assert!(set.find(&name("exact.example")).is_some());
+        assert!(set.find(&name("EXACT.example")).is_some());
+        assert!(set.find(&name("www.exact.example")).is_none());
+        assert!(set.find(&name("example")).is_none());
+
+        assert!(set.find(&name("tree.example")).is_some());
+        assert!(set.find(&name("a.b.tree.example")).is_some());
+        assert!(set.find(&name("nottree.example")).is_none());
+    }
+
+    #[test]
+    fn allowlists_override_blocks() {
+        let block = ("||ads.example^\nexact.example\n", false);
+        let allow = ("good.ads.example\n||cdn.ads.example^\nexact.example\n", true);
+
+        // whichever is loaded first.
+        for lists in [[block, allow], [allow, block]] {
+            let blocklist = blocklist(&lists);
+
+            assert_eq!(blocked(&blocklist, "ads.example"), Some(true));
+            assert_eq!(blocked(&blocklist, "www.ads.example"), Some(true));
+            assert_eq!(blocked(&blocklist, "good.ads.example"), Some(false));
+            assert_eq!(blocked(&blocklist, "www.good.ads.example"), Some(true));
+            assert_eq!(blocked(&blocklist, "cdn.ads.example"), Some(false));
+            assert_eq!(blocked(&blocklist, "img.cdn.ads.example"), Some(false));
+            assert_eq!(blocked(&blocklist, "exact.example"), Some(false));
+            assert_eq!(blocked(&blocklist, "example"), None);
+        }
+    }
+
+    #[test]
+    fn exceptions_override_blocks() {
+        let block = "||ads.example^\n";
+        let exception = "@@||cdn.ads.example^\n";
+
+        for text in [block.to_string() + exception, exception.to_string() + block] {
+            let blocklist = blocklist(&[(&text, false)]);
+
+            assert_eq!(blocked(&blocklist, "ads.example"), Some(true));
+            assert_eq!(blocked(&blocklist, "cdn.ads.example"), Some(false));
+            assert_eq!(blocked(&blocklist, "img.cdn.ads.example"), Some(false));
+        }
+
+        // exceptions in one list apply to blocks of another.
+        let blocklist = blocklist(&[("0.0.0.0 cdn.ads.example\n", false),
+                                    ("@@||ads.example^\n", false)]);
+        assert_eq!(blocked(&blocklist, "cdn.ads.example"), Some(false));
+
+        // the verdict tells where both entries came from.
+        let Some(Verdict::Allowed { block, allow }) = blocklist.check(&name("cdn.ads.example"))
+            else { panic!("not allowed") };
+        assert_eq!((block.line, block.rule.as_str()), (1, "0.0.0.0 cdn.ads.example"));
+        assert_eq!((allow.line, allow.rule.as_str()), (1, "@@||ads.example^"));
+    }
+
+    #[test]
+    fn block_responses() {
+        let a = MessageBuilder::query(name("ads.example"), record_type::A).build();
+        let aaaa = MessageBuilder::query(name("ads.example"), record_type::AAAA).build();
+        let mx = MessageBuilder::query(name("ads.example"), record_type::MX).build();
+        let data = |response: DNSPacket| -> Vec<RData> {
+            response.answers.into_iter().map(|r| r.data).collect()
+        };
+
+        let null: BlockResponse = "null".parse().unwrap();
+        assert_eq!(data(null.respond(&a, 300)), [RData::A(Ipv4Addr::UNSPECIFIED)]);
+        assert_eq!(data(null.respond(&aaaa, 300)), [RData::Aaaa(Ipv6Addr::UNSPECIFIED)]);
+        assert_eq!(null.respond(&a, 300).answers[0].ttl, 300);
+
+        let response = null.respond(&mx, 300);
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert!(response.answers.is_empty());
+
+        let nxdomain: BlockResponse = "nxdomain".parse().unwrap();
+        for query in [&a, &aaaa, &mx] {
+            let response = nxdomain.respond(query, 300);
+            assert_eq!(response.rcode(), rcode::NXDOMAIN);
+            assert!(response.answers.is_empty());
+        }
+
+        let sinkhole: BlockResponse = "10.0.0.1,10.0.0.2,fd00::1".parse().unwrap();
+        assert_eq!(data(sinkhole.respond(&a, 300)),
+                   [RData::A(Ipv4Addr::new(10, 0, 0, 1)), RData::A(Ipv4Addr::new(10, 0, 0, 2))]);
+        assert_eq!(data(sinkhole.respond(&aaaa, 300)), [RData::Aaaa("fd00::1".parse().unwrap())]);
+        assert!(sinkhole.respond(&mx, 300).answers.is_empty());
+
+        // ipv4 only sinkholes leave AAAA queries without records.
+        let sinkhole: BlockResponse = "10.0.0.1".parse().unwrap();
+        assert!(sinkhole.respond(&aaaa, 300).answers.is_empty());
+
+        assert!("10.0.0.1,bogus".parse::<BlockResponse>().is_err());
+        assert!("".parse::<BlockResponse>().is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! Building queries and responses without filling in a `DNSPacket` field by field.
+
+use crate::dns::*;
+use crate::upstream;
+
+/// Builds a message one section at a time.
+///
+/// ```
+/// use maldns::builder::MessageBuilder;
+/// use maldns::dns::{record_class, record_type, DomainName, RData, Record};
+///
+/// let name: DomainName = "example.com".parse().unwrap();
+/// let query = MessageBuilder::query(name.clone(), record_type::A).edns(1232, false).build();
+///
+/// let data = RData::A([192, 0, 2, 1].into());
+/// let response = MessageBuilder::response(&query)
+///     .answer(Record { name, class: record_class::IN, ttl: 300, data })
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    packet: DNSPacket,
+    /// Applied last, the upper bits need EDNS which may be added after it.
+    rcode: u16,
+}
+
+impl MessageBuilder {
+    /// Starts a query with a random id and recursion desired, asking for records of type `ty`
+    /// of `name` in class IN.
+    pub fn query(name: DomainName, ty: u16) -> Self {
+        let mut packet = DNSPacket::default();
+
+        packet.header.id = upstream::random() as u16;
+        packet.header.rd = 1;
+        packet.questions.push(Question { name, ty, class: record_class::IN });
+
+        Self { packet, rcode: rcode::NOERROR }
+    }
+
+    /// Starts an authoritative response to `query`, as made by `DNSPacket::reply`.
+    pub fn response(query: &DNSPacket) -> Self {
+        Self { packet: query.reply(), rcode: rcode::NOERROR }
+    }
+
+    /// Replaces the message id.
+    pub fn id(mut self, id: u16) -> Self {
+        self.packet.header.id = id;
+        self
+    }
+
+    /// Sets whether the upstream should resolve the question recursively.
+    pub fn recursion_desired(mut self, rd: bool) -> Self {
+        self.packet.header.rd = rd as u8;
+        self
+    }
+
+    /// Sets whether the response is authoritative.
+    pub fn authoritative(mut self, aa: bool) -> Self {
+        self.packet.header.aa = aa as u8;
+        self
+    }
+
+    /// Sets the full response code, see `rcode` for the values.
+    pub fn rcode(mut self, rcode: u16) -> Self {
+        self.rcode = rcode;
+        self
+    }
+
+    /// Adds a question, in class IN.
+    pub fn question(mut self, name: DomainName, ty: u16) -> Self {
+        self.packet.questions.push(Question { name, ty, class: record_class::IN });
+        self
+    }
+
+    /// Adds EDNS advertising `payload_size` bytes, replacing any there was.
+    pub fn edns(mut self, payload_size: u16, dnssec_ok: bool) -> Self {
+        self.packet.edns = Some(Edns { dnssec_ok, ..Edns::new(payload_size) });
+        self
+    }
+
+    /// Adds a record to the answer section.
+    pub fn answer(mut self, record: Record) -> Self {
+        self.packet.answers.push(record);
+        self
+    }
+
+    /// Adds a record to the authority section.
+    pub fn authority(mut self, record: Record) -> Self {
+        self.packet.authorities.push(record);
+        self
+    }
+
+    /// Adds a record to the additional section, OPT records are added with `edns` instead.
+    pub fn additional(mut self, record: Record) -> Self {
+        self.packet.additionals.push(record);
+        self
+    }
+
+    /// Finishes the message, ready to be serialized.
+    pub fn build(mut self) -> DNSPacket {
+        self.packet.set_rcode(self.rcode);
+        self.packet
+    }
+}
@@ -0,0 +1,937 @@
+//! Caching upstream responses, including negative and stale answers, and snapshotting them to disk.
+
+use crate::dns::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Longest an entry is kept, whatever the records say (RFC 8767 4 suggests a week at most).
+const MAX_TTL: u32 = 86400;
+
+/// Ttl of records served stale, as recommended by RFC 8767 4.
+const STALE_TTL: u32 = 30;
+
+/// Hits before an entry counts as popular enough to be prefetched.
+const PREFETCH_HITS: u32 = 3;
+
+/// Shortest ttl worth prefetching, shorter ones would be refreshed all the time.
+const PREFETCH_MIN_TTL: u32 = 10;
+
+/// How often the cache is written to its file when the configuration doesn't say.
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Start of every cache file, the version changes whenever the layout does.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"MDNSC001";
+
+/// Limits of the cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheConfig {
+    /// Most positive answers kept at once, 0 turns caching them off.
+    pub size: usize,
+    /// Most NXDOMAIN and NODATA answers kept at once, 0 turns caching them off.
+    pub negative_size: usize,
+    /// Longest a negative answer is kept, whatever its SOA says.
+    pub negative_max_ttl: u32,
+    /// How long after expiring answers are still served when no upstream answers, 0 turns
+    /// serving stale answers off.
+    pub stale_window: u32,
+    /// Whether popular answers are refreshed shortly before they expire.
+    pub prefetch: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        // RFC 2308 5 recommends one to three hours for negative answers, RFC 8767 5 one to
+        // three days for serving stale.
+        Self {
+            size: 10000,
+            negative_size: 2000,
+            negative_max_ttl: 3600,
+            stale_window: 86400,
+            prefetch: true,
+        }
+    }
+}
+
+/// What a cached response answers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: DomainName,
+    ty: u16,
+    class: u16,
+    /// Responses to queries with the DNSSEC OK bit carry signatures the others don't.
+    dnssec_ok: bool,
+}
+
+impl Key {
+    fn new(query: &DNSPacket) -> Option<Self> {
+        // responses to several questions at once are too rare to bother with.
+        let [question] = query.questions.as_slice() else { return None };
+
+        Some(Self {
+            name: question.name.clone(),
+            ty: question.ty,
+            class: question.class,
+            dnssec_ok: query.edns.as_ref().is_some_and(|e| e.dnssec_ok),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    rcode: u16,
+    answers: Vec<Record>,
+    authorities: Vec<Record>,
+    additionals: Vec<Record>,
+    /// Ttls count down from when the entry was stored.
+    stored: Instant,
+    /// Seconds the entry stays fresh, the lowest ttl of its records.
+    ttl: u32,
+    /// Client subnet the upstream limited the answer to, `None` if it's valid for everyone.
+    scope: Option<ClientSubnet>,
+    /// Times the entry was served.
+    hits: u32,
+    /// A refresh was started, so there's no need for another one.
+    prefetching: bool,
+    /// Tick of the last use, the entry with the lowest one is evicted first.
+    used: u64,
+}
+
+impl Entry {
+    /// The entry as a message, its key in the question and its DO bit and scope in the EDNS.
+    fn to_packet(&self, key: &Key) -> DNSPacket {
+        let mut edns = Edns::new(EDNS_PAYLOAD_SIZE);
+        edns.dnssec_ok = key.dnssec_ok;
+
+        if let Some(scope) = &self.scope {
+            edns.set_option(scope.to_option());
+        }
+
+        let mut packet = DNSPacket {
+            questions: vec![Question { name: key.name.clone(), ty: key.ty, class: key.class }],
+            answers: self.answers.clone(),
+            authorities: self.authorities.clone(),
+            additionals: self.additionals.clone(),
+            edns: Some(edns),
+            ..DNSPacket::default()
+        };
+
+        packet.set_rcode(self.rcode);
+        packet
+    }
+
+    /// Reverses `to_packet` for an entry stored `age` seconds ago.
+    fn from_packet(packet: DNSPacket, age: u64) -> Option<(Key, Entry)> {
+        let key = Key::new(&packet)?;
+        let stored = Instant::now().checked_sub(Duration::from_secs(age))?;
+
+        let ttl = packet.answers
+                        .iter()
+                        .chain(&packet.authorities)
+                        .chain(&packet.additionals)
+                        .map(|r| r.ttl)
+                        .min()?;
+
+        let entry = Entry {
+            rcode: packet.rcode(),
+            scope: client_subnet(&packet).filter(|s| s.scope_prefix > 0),
+            answers: packet.answers,
+            authorities: packet.authorities,
+            additionals: packet.additionals,
+            stored,
+            ttl,
+            hits: 0,
+            prefetching: false,
+            used: 0,
+        };
+
+        Some((key, entry))
+    }
+
+    /// Whether the answer is valid for the client subnet `subnet` sent upstream.
+    fn covers(&self, subnet: Option<&ClientSubnet>) -> bool {
+        let Some(scope) = &self.scope else { return true };
+        let Some(subnet) = subnet else { return false };
+
+        subnet.address.is_ipv4() == scope.address.is_ipv4()
+            && subnet.source_prefix >= scope.scope_prefix
+            && mask_address(subnet.address, scope.scope_prefix) == scope.address
+    }
+}
+
+#[derive(Debug)]
+struct Entries {
+    entries: HashMap<Key, Entry>,
+    /// Keys by the tick they were last used at, least recently used first.
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    capacity: usize,
+    /// Seconds past expiry an entry is kept around to be served stale.
+    stale_window: u32,
+}
+
+impl Entries {
+    fn new(capacity: usize, stale_window: u32) -> Self {
+        Self { entries: HashMap::new(), order: BTreeMap::new(), tick: 0, capacity, stale_window }
+    }
+
+    /// Marks the entry of `key` as just used.
+    fn touch(&mut self, key: &Key) {
+        let Some(entry) = self.entries.get_mut(key) else { return };
+
+        self.tick += 1;
+        self.order.remove(&entry.used);
+        self.order.insert(self.tick, key.clone());
+        entry.used = self.tick;
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    /// Adds or replaces the entry of `key`, evicting the least recently used ones when full.
+    fn insert(&mut self, key: Key, entry: Entry) {
+        self.remove(&key);
+        self.entries.insert(key.clone(), entry);
+        self.touch(&key);
+
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Builds the response to `query` from the entry of `key` with the ttls counted down,
+    /// along with whether the entry is popular and about to expire, so worth a refresh.
+    ///
+    /// With `stale` only expired entries within the stale window are used instead, with
+    /// every ttl set to `STALE_TTL` (RFC 8767 4).
+    fn get(&mut self, key: &Key, query: &DNSPacket, subnet: Option<&ClientSubnet>, stale: bool)
+        -> Option<(DNSPacket, bool)> {
+        let stale_window = self.stale_window;
+        let entry = self.entries.get_mut(key)?;
+        let elapsed = entry.stored.elapsed().as_secs().min(u32::MAX as u64) as u32;
+
+        // not even good for serving stale any more.
+        if elapsed >= entry.ttl.saturating_add(stale_window) {
+            self.remove(key);
+            return None;
+        }
+
+        if (elapsed >= entry.ttl) != stale || !entry.covers(subnet) {
+            return None;
+        }
+
+        entry.hits = entry.hits.saturating_add(1);
+
+        // refreshed once within the last tenth of the ttl, before anyone sees it expire.
+        let prefetch = !stale
+            && !entry.prefetching
+            && entry.hits >= PREFETCH_HITS
+            && entry.ttl >= PREFETCH_MIN_TTL
+            && (entry.ttl - elapsed) * 10 <= entry.ttl;
+        entry.prefetching |= prefetch;
+
+        let age = |records: &Vec<Record>| -> Vec<Record> {
+            records.iter()
+                   .map(|r| {
+                       let ttl = if stale { STALE_TTL } else { r.ttl - elapsed };
+                       Record { ttl, ..r.clone() }
+                   })
+                   .collect()
+        };
+
+        let mut response = query.reply();
+
+        // it's someone else's data.
+        response.header.aa = 0;
+        response.set_rcode(entry.rcode);
+        response.answers = age(&entry.answers);
+        response.authorities = age(&entry.authorities);
+        response.additionals = age(&entry.additionals);
+
+        // the scope goes back to the client like the upstream's response would have.
+        if let (Some(edns), Some(scope)) = (response.edns.as_mut(), &entry.scope) {
+            edns.set_option(scope.to_option());
+        }
+
+        self.touch(key);
+        Some((response, prefetch))
+    }
+}
+
+/// Upstream responses kept around for as long as their records live, limited to a number of
+/// entries by evicting the least recently used ones.
+///
+/// Negative answers are kept apart so floods of lookups for names that don't exist can't
+/// push out the answers that do.
+#[derive(Debug)]
+pub struct Cache {
+    positive: Mutex<Entries>,
+    negative: Mutex<Entries>,
+    negative_max_ttl: u32,
+    prefetch: bool,
+    /// Held while writing a snapshot, so concurrent saves don't share the temporary file.
+    saving: Mutex<()>,
+}
+
+impl Cache {
+    /// Creates an empty cache sized by `config`.
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            positive: Mutex::new(Entries::new(config.size, config.stale_window)),
+            negative: Mutex::new(Entries::new(config.negative_size, config.stale_window)),
+            negative_max_ttl: config.negative_max_ttl,
+            prefetch: config.prefetch,
+            saving: Mutex::new(()),
+        }
+    }
+
+    /// Writes every entry to the file at `path`, returning how many were written.
+    ///
+    /// The file starts with `SNAPSHOT_MAGIC` and the unix time of the snapshot. Each entry
+    /// follows as its age in seconds, a byte telling whether it's negative and the length
+    /// prefixed wire format of `Entry::to_packet`, all numbers big endian. Least recently
+    /// used entries come first so loading them keeps their order.
+    pub fn save(&self, path: &str) -> Result<usize, String> {
+        let _saving = self.saving.lock().unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| e.to_string())?;
+
+        let mut bytes: Vec<u8> = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend_from_slice(&now.as_secs().to_be_bytes());
+
+        let mut count = 0;
+
+        for (store, negative) in [(&self.positive, false), (&self.negative, true)] {
+            let entries = store.lock().unwrap();
+
+            for key in entries.order.values() {
+                let entry = &entries.entries[key];
+                let packet = entry.to_packet(key).serialize();
+
+                bytes.extend_from_slice(&entry.stored.elapsed().as_secs().to_be_bytes());
+                bytes.push(negative as u8);
+                bytes.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(&packet);
+                count += 1;
+            }
+        }
+
+        // written aside first, so a crash halfway leaves the previous snapshot intact.
+        let temporary = format!("{}.tmp", path);
+
+        std::fs::write(&temporary, &bytes)
+            .and_then(|_| std::fs::rename(&temporary, path))
+            .map_err(|e| format!("couldn't write cache file {}: {}", path, e))?;
+
+        Ok(count)
+    }
+
+    /// Adds the entries of a file written by `save`, aged by the time that passed since,
+    /// returning how many are still of use. A missing file is an empty cache.
+    ///
+    /// Corrupt entries are skipped and a file cut short keeps the entries before the cut, only
+    /// a file that isn't a snapshot at all fails.
+    pub fn load(&self, path: &str) -> Result<usize, String> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("couldn't read cache file {}: {}", path, e)),
+        };
+
+        let mut rest = bytes.strip_prefix(SNAPSHOT_MAGIC.as_slice())
+            .ok_or(format!("{} is not a cache file.", path))?;
+
+        let truncated = || format!("cache file {} is truncated.", path);
+
+        let saved = u64::from_be_bytes(take::<8>(&mut rest).ok_or_else(truncated)?);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs();
+
+        // a clock that went backwards makes no entry younger.
+        let downtime = now.saturating_sub(saved);
+        let mut count = 0;
+
+        while !rest.is_empty() {
+            let (Some(age), Some([negative]), Some(length)) = 
+                (take::<8>(&mut rest), take::<1>(&mut rest), take::<4>(&mut rest)) else { 
+                eprintln!("Error: {}", truncated());
+                break;
+            };
+
+            let length = u32::from_be_bytes(length) as usize;
+            let Some(packet) = rest.get(..length) else { 
+                eprintln!("Error: {}", truncated());
+                break;
+            };
+            rest = &rest[length..];
+
+            // entries stand on their own, a bad one doesn't spoil the rest.
+            let packet = match PacketParser::new(packet).deserialize() {
+                Ok(packet) => packet,
+                Err(e) => {
+                    eprintln!("Error: skipping corrupt entry in cache file {}: {}", path, e);
+                    continue;
+                },
+            };
+
+            let age = u64::from_be_bytes(age).saturating_add(downtime);
+            let negative = negative == 1;
+
+            let Some((key, entry)) = Entry::from_packet(packet, age) else { continue };
+            let store = if negative { &self.negative } else { &self.positive };
+            let mut entries = store.lock().unwrap();
+
+            // expired past serving stale while we were down.
+            if age >= (entry.ttl as u64).saturating_add(entries.stale_window as u64) {
+                continue;
+            }
+
+            if entries.capacity > 0 {
+                entries.insert(key, entry);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Looks up an entry for `query` in both stores.
+    fn lookup(&self, query: &DNSPacket, subnet: Option<&ClientSubnet>, stale: bool)
+        -> Option<(DNSPacket, bool)> {
+        let key = Key::new(query)?;
+
+        let positive = self.positive.lock().unwrap().get(&key, query, subnet, stale);
+        positive.or_else(|| self.negative.lock().unwrap().get(&key, query, subnet, stale))
+    }
+
+    /// Builds the response to `query` from a fresh entry with the ttls counted down, along
+    /// with whether it should be prefetched.
+    ///
+    /// `subnet` is the client subnet the query would be forwarded with, answers scoped to
+    /// another subnet aren't served.
+    pub fn get(&self, query: &DNSPacket, subnet: Option<&ClientSubnet>)
+        -> Option<(DNSPacket, bool)> {
+        self.lookup(query, subnet, false)
+            .map(|(response, prefetch)| (response, prefetch && self.prefetch))
+    }
+
+    /// Builds the response to `query` from an expired entry, for when no upstream answers.
+    pub fn get_stale(&self, query: &DNSPacket, subnet: Option<&ClientSubnet>) -> Option<DNSPacket> {
+        self.lookup(query, subnet, true).map(|(response, _)| response)
+    }
+
+    /// Stores the upstream response to `query` if it's a complete answer, or a negative
+    /// answer with a SOA record to tell how long it holds (RFC 2308 5).
+    pub fn insert(&self, query: &DNSPacket, response: &DNSPacket) {
+        let Some(key) = Key::new(query) else { return };
+
+        if response.header.tc == 1 {
+            return;
+        }
+
+        // referrals and errors aren't answers.
+        let negative = match (response.rcode(), response.answers.is_empty()) {
+            (rcode::NOERROR, false) => false,
+            (rcode::NOERROR, true) | (rcode::NXDOMAIN, _) => true,
+            _ => return,
+        };
+
+        let mut authorities = response.authorities.clone();
+
+        if negative {
+            let Some(soa) = authorities.iter_mut().find(|r| r.ty() == record_type::SOA) else { return };
+
+            if let RData::Soa { minimum, .. } = soa.data {
+                soa.ttl = soa.ttl.min(minimum);
+            }
+        }
+
+        let max_ttl = if negative { self.negative_max_ttl } else { MAX_TTL };
+        let clamp = |records: Vec<Record>| -> Vec<Record> {
+            records.into_iter()
+                   .map(|r| Record { ttl: r.ttl.min(max_ttl), ..r })
+                   .collect()
+        };
+
+        let answers = clamp(response.answers.clone());
+        let authorities = clamp(authorities);
+        let additionals = clamp(response.additionals.clone());
+
+        let ttl = answers.iter()
+                         .chain(&authorities)
+                         .chain(&additionals)
+                         .map(|r| r.ttl)
+                         .min()
+                         .unwrap_or(0);
+
+        if ttl == 0 {
+            return;
+        }
+
+        // answers tailored to a client subnet only apply to clients within it.
+        let scope = client_subnet(response)
+            .filter(|s| s.scope_prefix > 0)
+            .map(|s| ClientSubnet { address: mask_address(s.address, s.scope_prefix), ..s });
+
+        let entry = Entry {
+            rcode: response.rcode(),
+            answers,
+            authorities,
+            additionals,
+            stored: Instant::now(),
+            ttl,
+            scope,
+            hits: 0,
+            prefetching: false,
+            used: 0,
+        };
+
+        let (entries, other) = match negative {
+            true => (&self.negative, &self.positive),
+            false => (&self.positive, &self.negative),
+        };
+
+        // an answer of the other kind is outdated now.
+        other.lock().unwrap().remove(&key);
+
+        let mut entries = entries.lock().unwrap();
+
+        if entries.capacity > 0 {
+            entries.insert(key, entry);
+        }
+    }
+}
+
+/// Splits the first `N` bytes off `bytes`.
+fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    let taken = bytes.get(..N)?.try_into().ok()?;
+    *bytes = &bytes[N..];
+    Some(taken)
+}
+
+/// The client subnet a message carries, if any.
+pub fn client_subnet(message: &DNSPacket) -> Option<ClientSubnet> {
+    message.edns
+           .as_ref()
+           .and_then(|e| e.option(option_code::CLIENT_SUBNET))
+           .and_then(|o| ClientSubnet::from_option(o).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::MessageBuilder;
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn query(s: &str) -> DNSPacket {
+        MessageBuilder::query(name(s), record_type::A).build()
+    }
+
+    fn config(size: usize, negative_size: usize) -> CacheConfig {
+        CacheConfig { size, negative_size, ..CacheConfig::default() }
+    }
+
+    /// An answer to `query` with one address living for `ttl` seconds.
+    fn answer(query: &DNSPacket, ttl: u32) -> DNSPacket {
+        let data = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+        let name = query.questions[0].name.clone();
+
+        MessageBuilder::response(query)
+            .authoritative(false)
+            .answer(Record { name, class: record_class::IN, ttl, data })
+            .build()
+    }
+
+    /// A negative answer to `query` with `rcode` and a SOA living for `ttl` seconds whose
+    /// minimum is `minimum`.
+    fn negative(query: &DNSPacket, rcode: u16, ttl: u32, minimum: u32) -> DNSPacket {
+        let data = RData::Soa {
+            mname: name("ns.example"),
+            rname: name("hostmaster.example"),
+            serial: 1,
+            refresh: 3600,
+            retry: 900,
+            expire: 604800,
+            minimum,
+        };
+
+        MessageBuilder::response(query)
+            .authoritative(false)
+            .rcode(rcode)
+            .authority(Record { name: name("example"), class: record_class::IN, ttl, data })
+            .build()
+    }
+
+    /// A path in a fresh directory, removed again by `remove_path`.
+    fn snapshot_path() -> String {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let directory = std::env::temp_dir().join(format!("maldns-cache-{}-{}",
+            std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        directory.join("cache").to_str().unwrap().to_string()
+    }
+
+    fn remove_path(path: &str) {
+        std::fs::remove_dir_all(Path::new(path).parent().unwrap()).unwrap();
+    }
+
+    /// Makes the entry for `query` as old as if it was stored `seconds` ago.
+    fn age(cache: &Cache, query: &DNSPacket, seconds: u64) {
+        let key = Key::new(query).unwrap();
+
+        for store in [&cache.positive, &cache.negative] {
+            if let Some(entry) = store.lock().unwrap().entries.get_mut(&key) {
+                entry.stored = Instant::now().checked_sub(Duration::from_secs(seconds)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn ttls_count_down() {
+        let cache = Cache::new(CacheConfig::default());
+        let query = query("example.com");
+
+        cache.insert(&query, &answer(&query, 300));
+        age(&cache, &query, 100);
+
+        let (response, _) = cache.get(&query, None).unwrap();
+
+        assert_eq!(response.answers[0].ttl, 200);
+        assert_eq!(response.header.aa, 0);
+
+        // expired entries aren't fresh answers anymore.
+        age(&cache, &query, 300);
+        assert!(cache.get(&query, None).is_none());
+    }
+
+    #[test]
+    fn responses_take_the_query_id() {
+        let cache = Cache::new(CacheConfig::default());
+        let first = MessageBuilder::query(name("example.com"), record_type::A).id(1).build();
+        let second = MessageBuilder::query(name("example.com"), record_type::A).id(2).build();
+
+        cache.insert(&first, &answer(&first, 300));
+
+        let (response, _) = cache.get(&second, None).unwrap();
+        assert_eq!(response.header.id, 2);
+        assert_eq!(response.questions, second.questions);
+
+        // other types or names are other entries.
+        let other = MessageBuilder::query(name("example.com"), record_type::AAAA).build();
+        assert!(cache.get(&other, None).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_go_first() {
+        let cache = Cache::new(config(2, 2));
+        let (a, b, c) = (query("a.example"), query("b.example"), query("c.example"));
+
+        cache.insert(&a, &answer(&a, 300));
+        cache.insert(&b, &answer(&b, 300));
+        assert!(cache.get(&a, None).is_some());
+        cache.insert(&c, &answer(&c, 300));
+
+        assert!(cache.get(&a, None).is_some());
+        assert!(cache.get(&b, None).is_none());
+        assert!(cache.get(&c, None).is_some());
+
+        // a size of 0 caches nothing.
+        let cache = Cache::new(config(0, 0));
+        cache.insert(&a, &answer(&a, 300));
+        assert!(cache.get(&a, None).is_none());
+    }
+
+    #[test]
+    fn failures_and_truncated_responses_are_not_stored() {
+        let cache = Cache::new(CacheConfig::default());
+        let query = query("example.com");
+
+        let mut truncated = answer(&query, 300);
+        truncated.header.tc = 1;
+        cache.insert(&query, &truncated);
+        assert!(cache.get(&query, None).is_none());
+
+        for rcode in [rcode::SERVFAIL, rcode::REFUSED, rcode::FORMERR] {
+            let mut failed = answer(&query, 300);
+            failed.set_rcode(rcode);
+            cache.insert(&query, &failed);
+            assert!(cache.get(&query, None).is_none());
+        }
+
+        // nothing to count down from.
+        cache.insert(&query, &answer(&query, 0));
+        assert!(cache.get(&query, None).is_none());
+    }
+
+    #[test]
+    fn negative_ttls() {
+        let cache = Cache::new(CacheConfig { negative_max_ttl: 1000, ..CacheConfig::default() });
+        let soa_ttl = |response: &DNSPacket| response.authorities[0].ttl;
+
+        // the lower of the SOA ttl and its minimum (RFC 2308 5).
+        let nxdomain = query("missing.example");
+        cache.insert(&nxdomain, &negative(&nxdomain, rcode::NXDOMAIN, 900, 600));
+        let (response, _) = cache.get(&nxdomain, None).unwrap();
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+        assert_eq!(soa_ttl(&response), 600);
+
+        let nodata = query("nodata.example");
+        cache.insert(&nodata, &negative(&nodata, rcode::NOERROR, 300, 600));
+        let (response, _) = cache.get(&nodata, None).unwrap();
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert_eq!(soa_ttl(&response), 300);
+
+        // capped by `negative-max-ttl`.
+        let capped = query("capped.example");
+        cache.insert(&capped, &negative(&capped, rcode::NXDOMAIN, 7200, 3600));
+        let (response, _) = cache.get(&capped, None).unwrap();
+        assert_eq!(soa_ttl(&response), 1000);
+
+        age(&cache, &capped, 1000);
+        assert!(cache.get(&capped, None).is_none());
+    }
+
+    #[test]
+    fn negative_answers_need_a_soa() {
+        let cache = Cache::new(CacheConfig::default());
+        let query = query("missing.example");
+
+        let mut response = negative(&query, rcode::NXDOMAIN, 900, 600);
+        response.authorities.clear();
+        cache.insert(&query, &response);
+
+        assert!(cache.get(&query, None).is_none());
+    }
+
+    #[test]
+    fn negative_answers_are_kept_apart() {
+        let cache = Cache::new(config(2, 1));
+        let (a, b) = (query("a.example"), query("b.example"));
+        let (missing, gone) = (query("missing.example"), query("gone.example"));
+
+        cache.insert(&a, &answer(&a, 300));
+        cache.insert(&b, &answer(&b, 300));
+        cache.insert(&missing, &negative(&missing, rcode::NXDOMAIN, 900, 600));
+        cache.insert(&gone, &negative(&gone, rcode::NXDOMAIN, 900, 600));
+
+        // the second negative answer only pushed out the first one.
+        assert!(cache.get(&a, None).is_some());
+        assert!(cache.get(&b, None).is_some());
+        assert!(cache.get(&missing, None).is_none());
+        assert!(cache.get(&gone, None).is_some());
+
+        // an answer replaces the negative one for the same question.
+        cache.insert(&gone, &answer(&gone, 300));
+        let (response, _) = cache.get(&gone, None).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert!(cache.negative.lock().unwrap().entries.is_empty());
+
+        // a size of 0 only turns the negative store off.
+        let cache = Cache::new(config(2, 0));
+        cache.insert(&missing, &negative(&missing, rcode::NXDOMAIN, 900, 600));
+        cache.insert(&a, &answer(&a, 300));
+        assert!(cache.get(&missing, None).is_none());
+        assert!(cache.get(&a, None).is_some());
+    }
+
+    #[test]
+    fn stale_answers() {
+        let cache = Cache::new(CacheConfig { stale_window: 100, ..CacheConfig::default() });
+        let query = query("example.com");
+        let mut response = answer(&query, 60);
+        response.answers.push(Record { ttl: 300, ..response.answers[0].clone() });
+
+        cache.insert(&query, &response);
+
+        // fresh entries aren't stale.
+        assert!(cache.get_stale(&query, None).is_none());
+
+        age(&cache, &query, 61);
+        assert!(cache.get(&query, None).is_none());
+
+        let stale = cache.get_stale(&query, None).unwrap();
+        assert_eq!(stale.answers.len(), 2);
+        assert!(stale.answers.iter().all(|r| r.ttl == STALE_TTL));
+
+        // past the window the entry is gone for good.
+        age(&cache, &query, 160);
+        assert!(cache.get_stale(&query, None).is_none());
+        assert!(cache.positive.lock().unwrap().entries.is_empty());
+
+        // a window of 0 turns serving stale off.
+        let cache = Cache::new(CacheConfig { stale_window: 0, ..CacheConfig::default() });
+        cache.insert(&query, &answer(&query, 60));
+        age(&cache, &query, 61);
+        assert!(cache.get_stale(&query, None).is_none());
+    }
+
+    #[test]
+    fn prefetch_once_near_expiry() {
+        let cache = Cache::new(CacheConfig::default());
+        let query = query("example.com");
+        let prefetch = || cache.get(&query, None).unwrap().1;
+
+        cache.insert(&query, &answer(&query, 100));
+
+        // popular, but far from expiring.
+        assert!(!(0..PREFETCH_HITS).any(|_| prefetch()));
+
+        // within the last tenth of the ttl, once.
+        age(&cache, &query, 95);
+        assert!(prefetch());
+        assert!(!prefetch());
+
+        // a new answer can be prefetched again, once it's popular.
+        cache.insert(&query, &answer(&query, 100));
+        age(&cache, &query, 95);
+        assert!(!prefetch());
+        assert!(!prefetch());
+        assert!(prefetch());
+
+        // unpopular entries aren't worth it.
+        let unpopular = self::query("unpopular.example");
+        cache.insert(&unpopular, &answer(&unpopular, 100));
+        age(&cache, &unpopular, 95);
+        assert!(!cache.get(&unpopular, None).unwrap().1);
+
+        let cache = Cache::new(CacheConfig { prefetch: false, ..CacheConfig::default() });
+        cache.insert(&query, &answer(&query, 100));
+        age(&cache, &query, 95);
+        assert!(!(0..PREFETCH_HITS + 1).any(|_| cache.get(&query, None).unwrap().1));
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = snapshot_path();
+        let cache = Cache::new(CacheConfig::default());
+        let (found, missing) = (query("example.com"), query("missing.example"));
+
+        cache.insert(&found, &answer(&found, 300));
+        cache.insert(&missing, &negative(&missing, rcode::NXDOMAIN, 900, 600));
+        age(&cache, &found, 100);
+
+        assert_eq!(cache.save(&path), Ok(2));
+
+        // pretend the snapshot was taken 50 seconds before it was.
+        let mut bytes = std::fs::read(&path).unwrap();
+        let saved = u64::from_be_bytes(bytes[8..16].try_into().unwrap()) - 50;
+        bytes[8..16].copy_from_slice(&saved.to_be_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+
+        let loaded = Cache::new(CacheConfig::default());
+        assert_eq!(loaded.load(&path), Ok(2));
+
+        // a second may tick by between saving and loading.
+        let (response, _) = loaded.get(&found, None).unwrap();
+        assert!((149..=150).contains(&response.answers[0].ttl), "{:?}", response.answers);
+
+        let (response, _) = loaded.get(&missing, None).unwrap();
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+        assert_eq!(loaded.positive.lock().unwrap().entries.len(), 1);
+        assert_eq!(loaded.negative.lock().unwrap().entries.len(), 1);
+
+        remove_path(&path);
+    }
+
+    #[test]
+    fn snapshot_drops_expired_entries() {
+        let path = snapshot_path();
+        let cache = Cache::new(CacheConfig { stale_window: 100, ..CacheConfig::default() });
+        let (fresh, stale, expired) = (query("a.example"), query("b.example"), query("c.example"));
+
+        cache.insert(&fresh, &answer(&fresh, 300));
+        cache.insert(&stale, &answer(&stale, 60));
+        cache.insert(&expired, &answer(&expired, 60));
+        age(&cache, &stale, 100);
+        age(&cache, &expired, 200);
+
+        assert_eq!(cache.save(&path), Ok(3));
+
+        let loaded = Cache::new(CacheConfig { stale_window: 100, ..CacheConfig::default() });
+        assert_eq!(loaded.load(&path), Ok(2));
+        assert!(loaded.get(&fresh, None).is_some());
+        assert!(loaded.get_stale(&stale, None).is_some());
+        assert!(loaded.get_stale(&expired, None).is_none());
+
+        remove_path(&path);
+    }
+
+    #[test]
+    fn bad_snapshots() {
+        let path = snapshot_path();
+        let cache = Cache::new(CacheConfig::default());
+
+        // no snapshot yet is an empty cache.
+        assert_eq!(cache.load(&path), Ok(0));
+
+        std::fs::write(&path, b"NOTACACHEFILE").unwrap();
+        assert!(cache.load(&path).is_err());
+
+        for name in ["a.example", "b.example"] {
+            let query = query(name);
+            cache.insert(&query, &answer(&query, 300));
+        }
+
+        cache.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        // cut anywhere, only a missing header fails and the whole entries are kept.
+        for length in 0..bytes.len() {
+            std::fs::write(&path, &bytes[..length]).unwrap();
+            let loaded = Cache::new(CacheConfig::default()).load(&path);
+
+            match length {
+                0..16 => assert!(loaded.is_err()),
+                _ => assert!(loaded.unwrap() < 2),
+            }
+        }
+
+        // a corrupt entry only loses itself.
+        let mut corrupt = bytes.clone();
+        // past the header, age, negative flag and length, the question count of the first.
+        corrupt[16 + 13 + 4] = 0xFF;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(Cache::new(CacheConfig::default()).load(&path), Ok(1));
+
+        remove_path(&path);
+    }
+
+    #[test]
+    fn failed_saves_keep_the_last_snapshot() {
+        let path = snapshot_path();
+        let cache = Cache::new(CacheConfig::default());
+        let (a, b) = (query("a.example"), query("b.example"));
+
+        cache.insert(&a, &answer(&a, 300));
+        assert_eq!(cache.save(&path), Ok(1));
+
+        // the temporary file can't be written over a directory.
+        std::fs::create_dir(format!("{}.tmp", path)).unwrap();
+        cache.insert(&b, &answer(&b, 300));
+        assert!(cache.save(&path).is_err());
+
+        let loaded = Cache::new(CacheConfig::default());
+        assert_eq!(loaded.load(&path), Ok(1));
+        assert!(loaded.get(&a, None).is_some());
+
+        remove_path(&path);
+    }
+}
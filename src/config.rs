@@ -0,0 +1,193 @@
+//! The configuration file and its directives.
+
+use crate::ecs::EcsPolicy;
+use crate::rules::{Rule, RuleOrder};
+use crate::blocklist::BlockResponse;
+use crate::cache::{CacheConfig, DEFAULT_SNAPSHOT_INTERVAL};
+use crate::dns::DomainName;
+use crate::upstream::{ForwardRule, Strategy, UpstreamConfig, DEFAULT_TIMEOUT};
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Runtime configuration, read from a file of `directive arguments...` lines.
+///
+/// Empty lines and anything after a `#` are ignored.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Handling of the EDNS Client Subnet option, `ecs strip|pass|add <v4 prefix> <v6 prefix>`.
+    pub ecs: EcsPolicy,
+    /// Where queries are forwarded to, `upstream <address:port> [timeout ms]`.
+    pub upstreams: Vec<UpstreamConfig>,
+    /// How an upstream is picked, `strategy failover|round-robin|fastest|random`.
+    pub strategy: Strategy,
+    /// Upstreams for a domain and everything below it, `forward <suffix> <address:port>...`.
+    pub forwards: Vec<ForwardRule>,
+    /// Rewrite rules, `rule <name match> <action> [options]`, see `Rule`.
+    pub rules: Vec<Rule>,
+    /// Which matching rule wins, `rules first-match|priority`.
+    pub rule_order: RuleOrder,
+    /// Files of blocked domains, `blocklist <path>`, see `Blocklist::load`.
+    pub blocklists: Vec<String>,
+    /// Files of domains exempt from blocking, `allowlist <path>`, in the blocklist formats.
+    pub allowlists: Vec<String>,
+    /// Answer to blocked queries, `block-response null|nxdomain|<address>[,<address>...]`.
+    pub block_response: BlockResponse,
+    /// Time to live of records in answers made up by rules and blocks, `local-ttl <seconds>`,
+    /// `DEFAULT_TTL` if `None`.
+    pub local_ttl: Option<u32>,
+    /// Limits of the cache, `cache-size <entries>`, `negative-cache-size <entries>`,
+    /// `negative-max-ttl <seconds>`, `serve-stale <seconds>` and `prefetch on|off`. Sizes of
+    /// 0 turn caching off.
+    pub cache: CacheConfig,
+    /// File the cache is kept in across restarts and how often it's written,
+    /// `cache-file <path> [interval seconds]`.
+    pub cache_file: Option<(String, Duration)>,
+    /// Zones we're authoritative for, read from master files, `zone <origin> <path>`.
+    pub zone_files: Vec<(DomainName, String)>,
+    /// Domains answered locally from host entries only, `local-zone <name>`.
+    pub local_zones: Vec<DomainName>,
+    /// Local names and their addresses, `local-host <address> <name>...`.
+    pub local_hosts: Vec<(IpAddr, Vec<DomainName>)>,
+    /// Files of host entries in `/etc/hosts` format, `hosts <path>`.
+    pub hosts_files: Vec<String>,
+}
+
+impl Config {
+    /// Reads and parses the configuration file at `path`.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read config {}: {}", path, e))?;
+
+        Self::parse(&text)
+    }
+
+    /// Parses the directives of a configuration file.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Config::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            let Some((&directive, arguments)) = words.split_first() else { continue };
+
+            config.apply(directive, arguments)
+                  .map_err(|e| format!("config line {}: {}", number + 1, e))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Applies a single directive to the configuration.
+    fn apply(&mut self, directive: &str, arguments: &[&str]) -> Result<(), String> {
+        match (directive, arguments) {
+            ("ecs", ["strip"]) => self.ecs = EcsPolicy::Strip,
+            ("ecs", ["pass"]) => self.ecs = EcsPolicy::Pass,
+            ("ecs", ["add", v4_prefix, v6_prefix]) => {
+                let v4_prefix = parse_prefix(v4_prefix, 32)?;
+                let v6_prefix = parse_prefix(v6_prefix, 128)?;
+                self.ecs = EcsPolicy::Add { v4_prefix, v6_prefix };
+            },
+            ("ecs", _) => return Err("expected `ecs strip|pass|add <v4> <v6>`".to_string()),
+            ("upstream", [address, timeout @ ..]) if timeout.len() <= 1 => {
+                let address = address.parse()
+                    .map_err(|_| format!("invalid upstream address `{}`", address))?;
+
+                let timeout = match timeout.first() {
+                    Some(ms) => Duration::from_millis(ms.parse()
+                        .map_err(|_| format!("invalid timeout `{}`", ms))?),
+                    None => DEFAULT_TIMEOUT,
+                };
+
+                self.upstreams.push(UpstreamConfig { address, timeout });
+            },
+            ("upstream", _) => return Err("expected `upstream <address:port> [timeout ms]`".to_string()),
+            ("strategy", [strategy]) => self.strategy = match *strategy {
+                "failover" => Strategy::Failover,
+                "round-robin" => Strategy::RoundRobin,
+                "fastest" => Strategy::Fastest,
+                "random" => Strategy::Random,
+                _ => return Err(format!("unknown strategy `{}`", strategy)),
+            },
+            ("strategy", _) => return Err("expected `strategy <strategy>`".to_string()),
+            ("forward", [suffix, addresses @ ..]) if !addresses.is_empty() => {
+                let suffix: DomainName = suffix.parse()?;
+
+                let upstreams = addresses
+                    .iter()
+                    .map(|a| {
+                        let address = a.parse().map_err(|_| format!("invalid address `{}`", a))?;
+                        Ok(UpstreamConfig { address, timeout: DEFAULT_TIMEOUT })
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+
+                self.forwards.push(ForwardRule { suffix, upstreams });
+            },
+            ("forward", _) => return Err("expected `forward <suffix> <address:port>...`".to_string()),
+            ("rule", _) => self.rules.push(arguments.join(" ").parse()?),
+            ("rules", ["first-match"]) => self.rule_order = RuleOrder::FirstMatch,
+            ("rules", ["priority"]) => self.rule_order = RuleOrder::Priority,
+            ("rules", _) => return Err("expected `rules first-match|priority`".to_string()),
+            ("blocklist", [path]) => self.blocklists.push(path.to_string()),
+            ("blocklist", _) => return Err("expected `blocklist <path>`".to_string()),
+            ("allowlist", [path]) => self.allowlists.push(path.to_string()),
+            ("allowlist", _) => return Err("expected `allowlist <path>`".to_string()),
+            ("block-response", [response]) => self.block_response = response.parse()?,
+            ("block-response", _) => return Err("expected `block-response <response>`".to_string()),
+            ("local-ttl", [ttl]) => self.local_ttl = Some(ttl.parse()
+                .map_err(|_| format!("invalid ttl `{}`", ttl))?),
+            ("local-ttl", _) => return Err("expected `local-ttl <seconds>`".to_string()),
+            ("cache-size", [size]) => self.cache.size = size.parse()
+                .map_err(|_| format!("invalid cache size `{}`", size))?,
+            ("cache-size", _) => return Err("expected `cache-size <entries>`".to_string()),
+            ("negative-cache-size", [size]) => self.cache.negative_size = size.parse()
+                .map_err(|_| format!("invalid cache size `{}`", size))?,
+            ("negative-cache-size", _) => return Err("expected `negative-cache-size <entries>`".to_string()),
+            ("negative-max-ttl", [ttl]) => self.cache.negative_max_ttl = ttl.parse()
+                .map_err(|_| format!("invalid ttl `{}`", ttl))?,
+            ("negative-max-ttl", _) => return Err("expected `negative-max-ttl <seconds>`".to_string()),
+            ("serve-stale", [window]) => self.cache.stale_window = window.parse()
+                .map_err(|_| format!("invalid stale window `{}`", window))?,
+            ("serve-stale", _) => return Err("expected `serve-stale <seconds>`".to_string()),
+            ("prefetch", ["on"]) => self.cache.prefetch = true,
+            ("prefetch", ["off"]) => self.cache.prefetch = false,
+            ("prefetch", _) => return Err("expected `prefetch on|off`".to_string()),
+            ("cache-file", [path, interval @ ..]) if interval.len() <= 1 => {
+                let interval = match interval.first() {
+                    Some(s) => Duration::from_secs(s.parse()
+                        .map_err(|_| format!("invalid interval `{}`", s))?),
+                    None => DEFAULT_SNAPSHOT_INTERVAL,
+                };
+
+                self.cache_file = Some((path.to_string(), interval));
+            },
+            ("cache-file", _) => return Err("expected `cache-file <path> [interval seconds]`".to_string()),
+            ("zone", [origin, path]) => self.zone_files.push((origin.parse()?, path.to_string())),
+            ("zone", _) => return Err("expected `zone <origin> <path>`".to_string()),
+            ("local-zone", [name]) => self.local_zones.push(name.parse()?),
+            ("local-zone", _) => return Err("expected `local-zone <name>`".to_string()),
+            ("local-host", [address, names @ ..]) if !names.is_empty() => {
+                let address = address.parse()
+                    .map_err(|_| format!("invalid address `{}`", address))?;
+
+                let names = names.iter().map(|n| n.parse()).collect::<Result<_, _>>()?;
+                self.local_hosts.push((address, names));
+            },
+            ("local-host", _) => return Err("expected `local-host <address> <name>...`".to_string()),
+            ("hosts", [path]) => self.hosts_files.push(path.to_string()),
+            ("hosts", _) => return Err("expected `hosts <path>`".to_string()),
+            _ => return Err(format!("unknown directive `{}`", directive)),
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a network prefix length no longer than `max`.
+fn parse_prefix(text: &str, max: u8) -> Result<u8, String> {
+    text.parse::<u8>()
+        .ok()
+        .filter(|&p| p <= max)
+        .ok_or(format!("invalid prefix length `{}`", text))
+}
@@ -1,23 +1,41 @@
-use deku::prelude::*;
+//! The wire format codec: messages, names, records and EDNS, parsed with `PacketParser` and
+//! written with `PacketSerializer`.
 
-use std::collections::HashMap;
-use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-/// Creates one vector of bytes from multiple deserialized structs.
-fn monolithize<T: DekuContainerWrite>(vector: &Vec<T>) -> Vec<u8> {
-    vector.iter()
-          .map(|s| s.to_bytes().unwrap())
-          .reduce(|acc, i| { [acc, i].concat() })
-          .unwrap_or(vec![]) // unwrap_or_default
-}
+/// Maximum length of a domain name in its wire format, including length bytes.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Largest offset a compression pointer can address.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Largest message a client without EDNS can receive over UDP (RFC 1035 4.2.1).
+pub const MAX_UDP_SIZE: usize = 512;
+
+/// UDP payload size we advertise, small enough to avoid fragmentation.
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
 
-#[derive(Debug, Default)]
+/// Largest message that fits in a UDP datagram or a TCP length prefix.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// A whole DNS message (RFC 1035 4.1).
+///
+/// Section counts in the header are ignored when serializing, they're taken from the sections.
+#[derive(Debug, Clone, Default)]
 pub struct DNSPacket {
+    /// Id, flags and response code.
     pub header: Header,
-    pub questions: Vec<Question>, 
+    /// Asked questions, usually just one.
+    pub questions: Vec<Question>,
+    /// Records answering the questions.
     pub answers: Vec<Record>,
+    /// Records pointing at the authority for the questions.
     pub authorities: Vec<Record>,
+    /// Records that may help with the answers, without the OPT record.
     pub additionals: Vec<Record>,
+    /// The OPT pseudo-record, kept out of `additionals`.
+    pub edns: Option<Edns>,
 }
 
 impl DNSPacket {
@@ -25,44 +43,173 @@ impl DNSPacket {
         questions: Vec<Question>, 
         answers: Vec<Record>, 
         authorities: Vec<Record>, 
-        additionals: Vec<Record>
+        additionals: Vec<Record>,
+        edns: Option<Edns>,
     ) -> Self { 
-        Self { header, questions, answers, authorities, additionals } 
+        Self { header, questions, answers, authorities, additionals, edns } 
+    }
+
+    /// Creates an empty response to this query, echoing its id, opcode, questions and EDNS.
+    ///
+    /// Responses we make up ourselves are authoritative, nobody else has the data.
+    pub fn reply(&self) -> DNSPacket {
+        let mut response = DNSPacket::default();
+
+        response.header.id = self.header.id;
+        response.header.qr = 1;
+        response.header.opcode = self.header.opcode;
+        response.header.aa = 1;
+        response.header.rd = self.header.rd;
+        response.header.ra = 1;
+        response.questions = self.questions.clone();
+        response.edns = self.edns.as_ref().map(|e| Edns { 
+            dnssec_ok: e.dnssec_ok, 
+            ..Edns::new(EDNS_PAYLOAD_SIZE) 
+        });
+
+        response
+    }
+
+    /// Creates a response answering the first question of this query with `data`, every
+    /// record living for `ttl` seconds.
+    pub fn synthesize(&self, data: impl IntoIterator<Item = RData>, ttl: u32) -> DNSPacket {
+        let mut response = self.reply();
+
+        if let Some(question) = self.questions.first() {
+            response.answers.extend(data.into_iter().map(|data| Record {
+                name: question.name.clone(),
+                class: question.class,
+                ttl,
+                data,
+            }));
+        }
+
+        response
+    }
+
+    /// Largest UDP response the sender of this packet can receive.
+    pub fn max_udp_size(&self) -> usize {
+        self.edns
+            .as_ref()
+            .map_or(MAX_UDP_SIZE, |e| (e.payload_size as usize).max(MAX_UDP_SIZE))
+    }
+
+    /// Full response code, including the upper bits carried by EDNS.
+    pub fn rcode(&self) -> u16 {
+        let extended = self.edns.as_ref().map_or(0, |e| e.extended_rcode as u16);
+        extended << 4 | self.header.r_code as u16
+    }
+
+    /// Sets the response code, the upper bits are dropped without EDNS.
+    pub fn set_rcode(&mut self, rcode: u16) {
+        self.header.r_code = (rcode & 0x0F) as u8;
+
+        if let Some(edns) = self.edns.as_mut() {
+            edns.extended_rcode = (rcode >> 4) as u8;
+        }
+    }
+
+    /// Creates a SERVFAIL response to this query, for when no answer can be had.
+    pub fn server_failure(&self) -> DNSPacket {
+        let mut response = self.reply();
+        response.header.aa = 0;
+        response.set_rcode(rcode::SERVFAIL);
+        response
+    }
+
+    /// Creates a BADVERS response to a query of an EDNS version other than 0, which tells the
+    /// client version 0 is the one we speak (RFC 6891 6.1.3).
+    pub fn bad_version(&self) -> DNSPacket {
+        let mut response = self.reply();
+        response.header.aa = 0;
+        response.set_rcode(rcode::BADVERS);
+        response
+    }
+
+    /// Creates a FORMERR response to a query that couldn't be parsed, echoing its id, opcode
+    /// and recursion desired flag (RFC 1035 4.1.1).
+    ///
+    /// `None` if not even the header can be read, or the message is a response itself, which
+    /// is better left unanswered.
+    pub fn format_error(message: &[u8]) -> Option<DNSPacket> {
+        let header = Header::from_wire(message.get(..HEADER_LENGTH)?.try_into().ok()?);
+
+        if header.qr == 1 {
+            return None;
+        }
+
+        let mut response = DNSPacket::default();
+
+        response.header.id = header.id;
+        response.header.qr = 1;
+        response.header.opcode = header.opcode;
+        response.header.rd = header.rd;
+        response.header.ra = 1;
+        response.set_rcode(rcode::FORMERR);
+
+        Some(response)
+    }
+
+    /// Drops records from the end until the packet serializes to at most `limit` bytes.
+    ///
+    /// Additional records are dropped first, one at a time. After that whole RRsets are dropped
+    /// from the authority and answer sections, which sets the truncated flag (RFC 2181 9).
+    pub fn truncate(&mut self, limit: usize) {
+        while self.serialize().len() > limit {
+            if self.additionals.pop().is_some() {
+                continue;
+            }
+
+            let section = match (self.authorities.is_empty(), self.answers.is_empty()) {
+                (false, _) => &mut self.authorities,
+                (true, false) => &mut self.answers,
+                (true, true) => break,
+            };
+
+            let last = section.pop().unwrap();
+
+            while section.last().is_some_and(|r| {
+                r.name == last.name && r.ty() == last.ty() && r.class == last.class
+            }) {
+                section.pop();
+            }
+
+            self.header.tc = 1;
+        }
     }
 
     /// Turns a `DNSPacket` into a slice of bytes.
+    ///
+    /// Section counts are taken from the section lengths, and names are compressed.
     pub fn serialize(&self) -> Vec<u8> {
-        // TODO: maybe return Option or Result and handle the unwrap.
-        [self.header.to_bytes().unwrap(), 
-            monolithize(&self.questions), 
-            monolithize(&self.answers), 
-            monolithize(&self.authorities), 
-            monolithize(&self.additionals)].concat()
+        PacketSerializer::new().serialize(self)
     }
 }
 
-#[derive(Debug, Default, PartialEq, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
+/// Length of the message header.
+const HEADER_LENGTH: usize = 12;
+
+/// The fixed 12 byte message header (RFC 1035 4.1.1), flags are 0 or 1.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Header {
-    // packet identifier
+    /// Packet identifier.
     pub id: u16,
         // flags
-        #[deku(bits = "1")]
-        qr: u8,     // query response 
-        #[deku(bits = "4")]
-        opcode: u8, // operation code
-        #[deku(bits = "1")]
-        aa: u8,     // authoritive answer
-        #[deku(bits = "1")]
-        tc: u8,     // truncated message 
-        #[deku(bits = "1")]
-        rd: u8,     // recursion desired
-        #[deku(bits = "1")]
-        ra: u8,     // recursion available
-        #[deku(bits = "3")]
+        /// Query response.
+        pub qr: u8,
+        /// Operation code.
+        pub opcode: u8,
+        /// Authoritive answer.
+        pub aa: u8,
+        /// Truncated message.
+        pub tc: u8,
+        /// Recursion desired.
+        pub rd: u8,
+        /// Recursion available.
+        pub ra: u8,
         z: u8,      // reserved (edns)
-        #[deku(bits = "4")]
-        r_code: u8, // response code
+        /// Lower 4 bits of the response code, see `DNSPacket::rcode`.
+        pub r_code: u8,
     // question count
     qd_count: u16,
     // answer count
@@ -73,168 +220,2137 @@ pub struct Header {
     ar_count: u16
 }
 
-#[derive(Debug, Default, PartialEq, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
-pub struct Question {
-    // domain name
-    #[deku(until = "|v: &u8| *v == 0")] 
-    pub name: Vec<u8>,
-    // type of query
-    ty: u16,
-    // class of query 
-    class: u16,
+impl Header {
+    /// Decodes the header from the first bytes of a message, flags are laid out as
+    /// `qr:1 opcode:4 aa:1 tc:1 rd:1 ra:1 z:3 rcode:4`.
+    pub fn from_wire(bytes: &[u8; HEADER_LENGTH]) -> Self {
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let (high, low) = (bytes[2], bytes[3]);
+
+        Self {
+            id: u16_at(0),
+            qr: high >> 7,
+            opcode: high >> 3 & 0x0F,
+            aa: high >> 2 & 1,
+            tc: high >> 1 & 1,
+            rd: high & 1,
+            ra: low >> 7,
+            z: low >> 4 & 0x07,
+            r_code: low & 0x0F,
+            qd_count: u16_at(4),
+            an_count: u16_at(6),
+            ns_count: u16_at(8),
+            ar_count: u16_at(10),
+        }
+    }
+
+    /// Encodes the header, flags wider than their field are cut to it.
+    pub fn to_wire(&self) -> [u8; HEADER_LENGTH] {
+        let high = (self.qr & 1) << 7 | (self.opcode & 0x0F) << 3 | (self.aa & 1) << 2 
+            | (self.tc & 1) << 1 | self.rd & 1;
+        let low = (self.ra & 1) << 7 | (self.z & 0x07) << 4 | self.r_code & 0x0F;
+
+        let mut bytes = [0; HEADER_LENGTH];
+        bytes[0..2].copy_from_slice(&self.id.to_be_bytes());
+        bytes[2] = high;
+        bytes[3] = low;
+
+        let counts = [self.qd_count, self.an_count, self.ns_count, self.ar_count];
+
+        for (i, count) in counts.iter().enumerate() {
+            bytes[4 + i * 2..6 + i * 2].copy_from_slice(&count.to_be_bytes());
+        }
+
+        bytes
+    }
+}
+
+/// Maximum length of a single label.
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// A domain name made of labels, compared case-insensitively (RFC 4343).
+#[derive(Debug, Clone, Default)]
+pub struct DomainName {
+    /// Raw label bytes without length bytes, the root label is implicit.
+    labels: Vec<Vec<u8>>,
+}
+
+impl DomainName {
+    /// The root name `.`.
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    /// Creates a name from raw labels, checking label and name length limits.
+    pub fn from_labels(labels: Vec<Vec<u8>>) -> Result<Self, String> {
+        if let Some(label) = labels.iter().find(|l| l.is_empty() || l.len() > MAX_LABEL_LENGTH) {
+            return Err(format!("invalid label length {}.", label.len()));
+        }
+
+        let name = Self { labels };
+
+        if name.wire_len() > MAX_NAME_LENGTH {
+            return Err(format!("name is longer than {} bytes.", MAX_NAME_LENGTH));
+        }
+
+        Ok(name)
+    }
+
+    /// Creates a name from its uncompressed wire format, which must end with the root label.
+    pub fn from_wire(bytes: &[u8]) -> Result<Self, String> {
+        let mut labels: Vec<Vec<u8>> = Vec::new();
+        let mut position = 0;
+
+        loop {
+            let length = *bytes.get(position).ok_or("name is missing the root label.")? as usize;
+
+            if length == 0 {
+                break;
+            }
+
+            let label = bytes.get(position + 1..position + 1 + length).ok_or("truncated label.")?;
+            labels.push(label.to_vec());
+            position += length + 1;
+        }
+
+        Self::from_labels(labels)
+    }
+
+    /// Turns the name into its uncompressed wire format.
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.wire_len());
+
+        for label in &self.labels {
+            bytes.push(label.len() as u8);
+            bytes.extend_from_slice(label);
+        }
+
+        bytes.push(0);
+        bytes
+    }
+
+    /// Length of the uncompressed wire format, including the root label.
+    pub fn wire_len(&self) -> usize {
+        self.labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1
+    }
+
+    /// Iterates over the labels from the leftmost one, excluding the root label.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        self.labels.iter().map(|l| l.as_slice())
+    }
+
+    /// Amount of labels, not counting the root label.
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Whether this is the root name `.`.
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The name with its leftmost label removed, `None` for the root.
+    pub fn parent(&self) -> Option<DomainName> {
+        (!self.is_root()).then(|| Self { labels: self.labels[1..].to_vec() })
+    }
+
+    /// Whether this name equals `other` or lies below it.
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        self.labels.len() >= other.labels.len() 
+            && self.labels
+                   .iter()
+                   .rev()
+                   .zip(other.labels.iter().rev())
+                   .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// Appends `suffix` to this name, as when completing a relative name with its origin.
+    pub fn concat(&self, suffix: &DomainName) -> Result<DomainName, String> {
+        Self::from_labels(self.labels.iter().chain(&suffix.labels).cloned().collect())
+    }
+
+    /// The name the PTR record of `address` lives at, under `in-addr.arpa` or `ip6.arpa`.
+    pub fn reverse(address: IpAddr) -> Self {
+        let (mut labels, suffix): (Vec<Vec<u8>>, [&[u8]; 2]) = match address {
+            IpAddr::V4(a) => (
+                a.octets().iter().rev().map(|o| o.to_string().into_bytes()).collect(),
+                [b"in-addr", b"arpa"],
+            ),
+            // one label per nibble, least significant first.
+            IpAddr::V6(a) => (
+                a.octets()
+                 .iter()
+                 .rev()
+                 .flat_map(|o| [o & 0xF, o >> 4])
+                 .map(|n| format!("{:x}", n).into_bytes())
+                 .collect(),
+                [b"ip6", b"arpa"],
+            ),
+        };
+
+        labels.extend(suffix.iter().map(|l| l.to_vec()));
+        Self { labels }
+    }
+
+    /// Whether this name lies strictly below `other`.
+    pub fn is_strict_subdomain_of(&self, other: &DomainName) -> bool {
+        self.labels.len() > other.labels.len() && self.is_subdomain_of(other)
+    }
+}
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len() && self.is_subdomain_of(other)
+    }
+}
+
+impl Eq for DomainName {}
+
+impl std::hash::Hash for DomainName {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        for label in &self.labels {
+            state.write_u8(label.len() as u8);
+            label.iter().for_each(|b| state.write_u8(b.to_ascii_lowercase()));
+        }
+    }
+}
+
+/// Presentation format (RFC 1035 5.1), without the trailing dot except for the root.
+impl std::fmt::Display for DomainName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_root() {
+            return write!(f, ".");
+        }
+
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+
+            for &byte in label {
+                match byte {
+                    b'.' | b'\\' => write!(f, "\\{}", byte as char)?,
+                    0x21..=0x7E => write!(f, "{}", byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
-impl Question {
-    /// Interpret the name in the DNS packet format as a rust string.
-    pub fn get_name_as_string(&self) -> String {
-        self.name[1..self.name.len() - 1]
-            .iter()
-            .map(|&c| if c < 0x30 { '.' } else { c as char })
-            .collect::<String>()
+/// Parses the presentation format, the trailing dot is optional.
+impl std::str::FromStr for DomainName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s == "." {
+            return Ok(Self::root());
+        }
+
+        let mut labels: Vec<Vec<u8>> = Vec::new();
+        let mut label: Vec<u8> = Vec::new();
+        let mut bytes = s.bytes().peekable();
+
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'.' => labels.push(std::mem::take(&mut label)),
+                b'\\' => {
+                    let escaped = bytes.next().ok_or(format!("dangling escape in '{}'.", s))?;
+
+                    if escaped.is_ascii_digit() {
+                        let digits = [escaped, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                        let value = std::str::from_utf8(&digits)
+                            .ok()
+                            .and_then(|d| d.parse::<u8>().ok())
+                            .ok_or(format!("invalid \\DDD escape in '{}'.", s))?;
+                        label.push(value);
+                    } else {
+                        label.push(escaped);
+                    }
+                },
+                _ => label.push(byte),
+            }
+
+            // a trailing dot terminates the name instead of starting an empty label.
+            if byte == b'.' && bytes.peek().is_none() {
+                return Self::from_labels(labels);
+            }
+        }
+
+        labels.push(label);
+        Self::from_labels(labels)
     }
 }
 
-#[derive(Debug, Default, PartialEq, DekuRead, DekuWrite)]
-#[deku(endian = "big")]
+/// An entry of the question section.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Question {
+    /// Domain name.
+    pub name: DomainName,
+    /// Type of query.
+    pub ty: u16,
+    /// Class of query.
+    pub class: u16,
+}
+
+/// A resource record of the answer, authority or additional section.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Record {
-    // domain name
-    // TODO: create a temp variable whose value is parse_name.len().
-    name: u16,  
-    // name of record
-    ty: u16,
-    // type of record
-    class: u16,
-    // time to live before cache expires
-    ttl: u32,
-    // length of data 
-    len: u16,
-    // the data for an A record
-    #[deku(count = "len", endian = "big")]
-    pub data: Vec<u8>
+    /// Domain name.
+    pub name: DomainName,
+    /// Class of record.
+    pub class: u16,
+    /// Time to live before cache expires.
+    pub ttl: u32,
+    /// The record data, which also determines the type of record.
+    pub data: RData,
 }
 
-pub struct PacketParser<'a> {
-    /// A buffer that *should* contain a DNS packet.
-    buffer: &'a [u8; 512],
-    /// A pointer to an unparsed packet position.
-    current: usize,
-    /// Holds offsets that map to decompressed names.
-    decompress_map: HashMap<u8, Vec<u8>>,
+impl Record {
+    /// Type of record, taken from its data.
+    pub fn ty(&self) -> u16 {
+        self.data.ty()
+    }
 }
 
-impl<'a> PacketParser<'a> {
-    pub fn new(buffer: &'a [u8; 512]) -> Self {
-        Self { buffer, current: 0, decompress_map: HashMap::new() }
-    } 
- 
-    /// Returns byte at current pointer position.
-    fn get_current_byte(&self) -> u8 {
-        self.buffer[self.current]
+/// Record type values (RFC 1035 3.2.2 and later).
+pub mod record_type {
+    /// IPv4 address.
+    pub const A: u16 = 1;
+    /// Authoritative name server.
+    pub const NS: u16 = 2;
+    /// Canonical name of an alias.
+    pub const CNAME: u16 = 5;
+    /// Start of a zone of authority.
+    pub const SOA: u16 = 6;
+    /// Domain name pointer, used for reverse lookups.
+    pub const PTR: u16 = 12;
+    /// Mail exchange (RFC 1035 3.3.9).
+    pub const MX: u16 = 15;
+    /// Text strings.
+    pub const TXT: u16 = 16;
+    /// IPv6 address (RFC 3596).
+    pub const AAAA: u16 = 28;
+    /// Service location (RFC 2782).
+    pub const SRV: u16 = 33;
+    /// Naming authority pointer (RFC 3403).
+    pub const NAPTR: u16 = 35;
+    /// EDNS pseudo-record (RFC 6891).
+    pub const OPT: u16 = 41;
+    /// SSH key fingerprint (RFC 4255).
+    pub const SSHFP: u16 = 44;
+    /// TLS certificate association (RFC 6698).
+    pub const TLSA: u16 = 52;
+    /// Service binding (RFC 9460).
+    pub const SVCB: u16 = 64;
+    /// Service binding for HTTPS (RFC 9460).
+    pub const HTTPS: u16 = 65;
+    /// Certification authority authorization (RFC 8659).
+    pub const CAA: u16 = 257;
+    /// Only valid in questions, asks for records of every type.
+    pub const ANY: u16 = 255;
+
+    const NAMES: [(&str, u16); 18] = [
+        ("A", A), ("NS", NS), ("CNAME", CNAME), ("SOA", SOA), ("PTR", PTR), ("MX", MX), 
+        ("TXT", TXT), ("AAAA", AAAA), ("SRV", SRV), ("NAPTR", NAPTR), ("OPT", OPT), 
+        ("SSHFP", SSHFP), ("TLSA", TLSA), ("SVCB", SVCB), ("HTTPS", HTTPS), ("CAA", CAA), 
+        ("ANY", ANY), ("*", ANY),
+    ];
+
+    /// Parses a type mnemonic or the RFC 3597 `TYPEnnn` form, ignoring case.
+    pub fn from_name(name: &str) -> Option<u16> {
+        let upper = name.to_ascii_uppercase();
+
+        NAMES.iter()
+             .find(|(n, _)| *n == upper)
+             .map(|&(_, ty)| ty)
+             .or_else(|| upper.strip_prefix("TYPE")?.parse().ok())
     }
+}
+
+/// Response code values (RFC 1035 4.1.1).
+pub mod rcode {
+    /// No error.
+    pub const NOERROR: u16 = 0;
+    /// The query couldn't be interpreted.
+    pub const FORMERR: u16 = 1;
+    /// The server failed to answer.
+    pub const SERVFAIL: u16 = 2;
+    /// The queried name doesn't exist.
+    pub const NXDOMAIN: u16 = 3;
+    /// The server won't answer the query.
+    pub const REFUSED: u16 = 5;
+    /// Extended, only carried along with EDNS (RFC 6891 9).
+    pub const BADVERS: u16 = 16;
+}
+
+/// Record class values (RFC 1035 3.2.4).
+pub mod record_class {
+    /// The internet.
+    pub const IN: u16 = 1;
+}
+
+/// Decoded record data of the common record types.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    /// IPv4 address.
+    A(Ipv4Addr),
+    /// IPv6 address.
+    Aaaa(Ipv6Addr),
+    /// Canonical name the owner is an alias of.
+    Cname(DomainName),
+    /// Name server of the zone.
+    Ns(DomainName),
+    /// Name the owner points to.
+    Ptr(DomainName),
+    /// Mail exchange.
+    Mx {
+        /// Lower values are preferred.
+        preference: u16,
+        /// Host accepting mail for the owner.
+        exchange: DomainName,
+    },
+    /// One or more character strings.
+    Txt(Vec<Vec<u8>>),
+    /// Start of authority, whose `minimum` is also the negative caching TTL (RFC 2308).
+    Soa {
+        /// Primary name server of the zone.
+        mname: DomainName,
+        /// Mailbox of the person responsible, with the `@` as the first dot.
+        rname: DomainName,
+        /// Version of the zone.
+        serial: u32,
+        /// Seconds before secondaries check for a new serial.
+        refresh: u32,
+        /// Seconds before secondaries retry a failed refresh.
+        retry: u32,
+        /// Seconds after which secondaries stop answering without a refresh.
+        expire: u32,
+        /// TTL of negative answers.
+        minimum: u32,
+    },
+    /// Service location.
+    Srv {
+        /// Lower values are tried first.
+        priority: u16,
+        /// Relative weight among targets of the same priority.
+        weight: u16,
+        /// Port the service listens on.
+        port: u16,
+        /// Host providing the service, never compressed.
+        target: DomainName,
+    },
+    /// Naming authority pointer.
+    Naptr {
+        /// Lower values are processed first.
+        order: u16,
+        /// Lower values are preferred among records of the same order.
+        preference: u16,
+        /// Flags controlling how the record is rewritten.
+        flags: Vec<u8>,
+        /// Services available down this path.
+        services: Vec<u8>,
+        /// Substitution expression applied to the client string.
+        regexp: Vec<u8>,
+        /// Next name to look up when `regexp` is empty, never compressed.
+        replacement: DomainName,
+    },
+    /// SSH host key fingerprint.
+    Sshfp {
+        /// Algorithm of the key.
+        algorithm: u8,
+        /// Hash used for the fingerprint.
+        fingerprint_type: u8,
+        /// The fingerprint itself.
+        fingerprint: Vec<u8>,
+    },
+    /// TLS certificate association.
+    Tlsa {
+        /// How the certificate is to be matched against.
+        usage: u8,
+        /// Whether the whole certificate or just its public key is matched.
+        selector: u8,
+        /// How `data` is derived from the selected part.
+        matching_type: u8,
+        /// Certificate association data.
+        data: Vec<u8>,
+    },
+    /// Service binding.
+    Svcb(ServiceBinding),
+    /// Service binding for HTTPS origins.
+    Https(ServiceBinding),
+    /// Certification authority authorization.
+    Caa {
+        /// Bit 7 marks the property critical.
+        flags: u8,
+        /// Property name, such as `issue`.
+        tag: Vec<u8>,
+        /// Property value.
+        value: Vec<u8>,
+    },
+    /// Data of a type we don't decode, kept as is along with its type.
+    Unknown(u16, Vec<u8>),
+}
 
-    /// Returns whether or not current byte is a compressed name jump opcode.
-    fn is_current_jmp(&mut self) -> bool {
-       self.get_current_byte() & 0xC0 == 0xC0 
+impl RData {
+    /// Decodes uncompressed record data of type `ty`, as written in the RFC 3597 generic format.
+    pub fn from_wire(ty: u16, bytes: &[u8]) -> Result<RData, ParseError> {
+        PacketParser::new(bytes).parse_rdata(ty, bytes.len())
     }
 
-    /// Shows byte one byte ahead without consuming it.
-    fn peek(&self) -> Option<u8> {
-        self.buffer.get(self.current + 1).copied() 
+    /// Type of record this data belongs to.
+    pub fn ty(&self) -> u16 {
+        match self {
+            RData::A(_) => record_type::A,
+            RData::Aaaa(_) => record_type::AAAA,
+            RData::Cname(_) => record_type::CNAME,
+            RData::Ns(_) => record_type::NS,
+            RData::Ptr(_) => record_type::PTR,
+            RData::Mx { .. } => record_type::MX,
+            RData::Txt(_) => record_type::TXT,
+            RData::Soa { .. } => record_type::SOA,
+            RData::Srv { .. } => record_type::SRV,
+            RData::Naptr { .. } => record_type::NAPTR,
+            RData::Sshfp { .. } => record_type::SSHFP,
+            RData::Tlsa { .. } => record_type::TLSA,
+            RData::Svcb(_) => record_type::SVCB,
+            RData::Https(_) => record_type::HTTPS,
+            RData::Caa { .. } => record_type::CAA,
+            RData::Unknown(ty, _) => *ty,
+        }
     }
+}
 
-    /// Count the number of bytes until peeked byte = 0.
-    fn get_name_length(&self) -> usize {
-        self.buffer
-            .iter()
-            .skip(self.current)
-            .take_while(|&&b| b != 0)
-            .count() + 1
+impl Default for RData {
+    fn default() -> Self {
+        RData::Unknown(0, Vec::new())
     }
+}
 
-    /// Gets range of bytes starting from `current` to `n`.
+/// Service binding data shared by SVCB and HTTPS records (RFC 9460).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceBinding {
+    /// 0 for alias mode, otherwise the service priority.
+    pub priority: u16,
+    /// Name of the service endpoint, or the alias target.
+    pub target: DomainName,
+    /// Parameters of the endpoint, ordered by key.
+    pub params: Vec<SvcParam>,
+}
+
+impl ServiceBinding {
+    /// Replaces the address hints with `addresses`, dropping hints of the other family.
     ///
-    /// Makes sure it doesn't overstep its bounds out of the buffer.
-    fn advance_n(&mut self, n: usize) -> Result<&[u8], String> {
-        match self.buffer.get(self.current + n).copied() {
-            None => Err(format!("couldn't advance far enough. [{}/{}]\n\n{}", 
-                    self.current + n + 1, self.buffer.len(), 
-                    Backtrace::force_capture())),
-            Some(_) => { 
-                self.current += n; 
-                Ok(&self.buffer[self.current - n..self.current]) 
+    /// ECH configs are removed as well so clients can't use them to bypass a redirect.
+    pub fn redirect_hints(&mut self, v4: &[Ipv4Addr], v6: &[Ipv6Addr]) {
+        self.params.retain(|p| {
+            !matches!(p, SvcParam::Ipv4Hint(_) | SvcParam::Ipv6Hint(_) | SvcParam::Ech(_))
+        });
+
+        if !v4.is_empty() {
+            self.params.push(SvcParam::Ipv4Hint(v4.to_vec()));
+        }
+
+        if !v6.is_empty() {
+            self.params.push(SvcParam::Ipv6Hint(v6.to_vec()));
+        }
+
+        // keys that are gone can't stay mandatory, and an empty mandatory list isn't valid.
+        let present: Vec<u16> = self.params.iter().map(|p| p.key()).collect();
+
+        for param in &mut self.params {
+            if let SvcParam::Mandatory(keys) = param {
+                keys.retain(|k| present.contains(k));
+            }
+        }
+
+        self.params.retain(|p| !matches!(p, SvcParam::Mandatory(keys) if keys.is_empty()));
+
+        self.params.sort_by_key(|p| p.key());
+    }
+}
+
+/// A single service parameter of a `ServiceBinding`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SvcParam {
+    /// Keys a client has to understand to use the record.
+    Mandatory(Vec<u16>),
+    /// Protocol ids the endpoint supports.
+    Alpn(Vec<Vec<u8>>),
+    /// The default protocol isn't supported.
+    NoDefaultAlpn,
+    /// Port of the endpoint.
+    Port(u16),
+    /// Addresses of the target, to save a lookup.
+    Ipv4Hint(Vec<Ipv4Addr>),
+    /// Encrypted ClientHello configuration.
+    Ech(Vec<u8>),
+    /// Addresses of the target, to save a lookup.
+    Ipv6Hint(Vec<Ipv6Addr>),
+    /// A key we don't decode, along with its value.
+    Unknown(u16, Vec<u8>),
+}
+
+impl SvcParam {
+    /// Key of the parameter (RFC 9460 14.3.2).
+    pub fn key(&self) -> u16 {
+        match self {
+            SvcParam::Mandatory(_) => 0,
+            SvcParam::Alpn(_) => 1,
+            SvcParam::NoDefaultAlpn => 2,
+            SvcParam::Port(_) => 3,
+            SvcParam::Ipv4Hint(_) => 4,
+            SvcParam::Ech(_) => 5,
+            SvcParam::Ipv6Hint(_) => 6,
+            SvcParam::Unknown(key, _) => *key,
+        }
+    }
+
+    /// Decodes the value of the parameter with the given key.
+    pub fn from_wire(key: u16, value: &[u8]) -> Result<Self, String> {
+        let invalid = || format!("invalid value for svc param key{}.", key);
+
+        let param = match key {
+            0 if !value.is_empty() && value.len().is_multiple_of(2) => SvcParam::Mandatory(
+                value.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect()
+            ),
+            1 => {
+                let mut ids: Vec<Vec<u8>> = Vec::new();
+                let mut position = 0;
+
+                while position < value.len() {
+                    let length = value[position] as usize;
+                    let id = value.get(position + 1..position + 1 + length).ok_or_else(invalid)?;
+                    ids.push(id.to_vec());
+                    position += length + 1;
+                }
+
+                if ids.is_empty() {
+                    return Err(invalid());
+                }
+
+                SvcParam::Alpn(ids)
             },
-        } 
+            2 if value.is_empty() => SvcParam::NoDefaultAlpn,
+            3 if value.len() == 2 => SvcParam::Port(u16::from_be_bytes([value[0], value[1]])),
+            4 if !value.is_empty() && value.len().is_multiple_of(4) => SvcParam::Ipv4Hint(
+                value.chunks_exact(4)
+                     .map(|c| Ipv4Addr::from(<[u8; 4]>::try_from(c).unwrap()))
+                     .collect()
+            ),
+            5 => SvcParam::Ech(value.to_vec()),
+            6 if !value.is_empty() && value.len().is_multiple_of(16) => SvcParam::Ipv6Hint(
+                value.chunks_exact(16)
+                     .map(|c| Ipv6Addr::from(<[u8; 16]>::try_from(c).unwrap()))
+                     .collect()
+            ),
+            0..=6 => return Err(invalid()),
+            _ => SvcParam::Unknown(key, value.to_vec()),
+        };
+
+        Ok(param)
     }
-    
-    /// Parses variable length name field from bytes.
-    ///
-    /// Increments position pointer by name length and returns vector of name bytes.
-    fn parse_name(&mut self) -> Result<Vec<u8>, String> {
-        if self.is_current_jmp() { 
-            return Ok(self.advance_n(2)?.to_vec());
-        } 
 
-        let name: Vec<u8> = self.advance_n(self.get_name_length())?.to_vec();
-        
-        // NOTE: currently inserts `current` after its be modified, need to log before or subtract
-        // by name length, also do something about the clone there, later. 
-        self.decompress_map.insert(self.current as u8, name.clone());
-        
-        Ok(name)
+    /// Encodes the value of the parameter, without its key and length.
+    pub fn to_wire(&self) -> Vec<u8> {
+        match self {
+            SvcParam::Mandatory(keys) => keys.iter().flat_map(|k| k.to_be_bytes()).collect(),
+            SvcParam::Alpn(ids) => ids.iter()
+                                      .flat_map(|id| [&[id.len() as u8][..], id].concat())
+                                      .collect(),
+            SvcParam::NoDefaultAlpn => Vec::new(),
+            SvcParam::Port(port) => port.to_be_bytes().to_vec(),
+            SvcParam::Ipv4Hint(addresses) => addresses.iter().flat_map(|a| a.octets()).collect(),
+            SvcParam::Ech(config) => config.clone(),
+            SvcParam::Ipv6Hint(addresses) => addresses.iter().flat_map(|a| a.octets()).collect(),
+            SvcParam::Unknown(_, value) => value.clone(),
+        }
+    }
+}
+
+/// Names of the known service parameter keys, used in presentation format.
+fn svc_param_key_name(key: u16) -> Option<&'static str> {
+    ["mandatory", "alpn", "no-default-alpn", "port", "ipv4hint", "ech", "ipv6hint"]
+        .get(key as usize)
+        .copied()
+}
+
+/// Presentation format (RFC 9460 2.1).
+impl std::fmt::Display for SvcParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match svc_param_key_name(self.key()) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "key{}", self.key())?,
+        }
+
+        match self {
+            SvcParam::Mandatory(keys) => {
+                write!(f, "=")?;
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    match svc_param_key_name(*key) {
+                        Some(name) => write!(f, "{}", name)?,
+                        None => write!(f, "key{}", key)?,
+                    }
+                }
+                Ok(())
+            },
+            SvcParam::Alpn(ids) => {
+                write!(f, "=")?;
+                for (i, id) in ids.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    for &byte in id {
+                        match byte {
+                            b',' | b'\\' => write!(f, "\\{}", byte as char)?,
+                            0x21..=0x7E if byte != b'"' => write!(f, "{}", byte as char)?,
+                            _ => write!(f, "\\{:03}", byte)?,
+                        }
+                    }
+                }
+                Ok(())
+            },
+            SvcParam::NoDefaultAlpn => Ok(()),
+            SvcParam::Port(port) => write!(f, "={}", port),
+            SvcParam::Ipv4Hint(addresses) => {
+                let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+                write!(f, "={}", addresses.join(","))
+            },
+            SvcParam::Ech(config) => write!(f, "={}", base64(config)),
+            SvcParam::Ipv6Hint(addresses) => {
+                let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+                write!(f, "={}", addresses.join(","))
+            },
+            SvcParam::Unknown(_, value) => {
+                write!(f, "=")?;
+                fmt_character_string(f, value)
+            },
+        }
     }
+}
 
-    fn parse_record(&mut self, record_count: usize) -> Result<Vec<Record>, String> {
-        let mut records: Vec<Record> = Vec::with_capacity(record_count);
+/// Standard base64 with padding, used for presentation of binary values.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = 
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 
-        for _ in 0..records.capacity() {
-            let mut record_bytes = self.parse_name()?;
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
 
-            // add bytes past name bytes until data length field.
-            record_bytes.extend_from_slice(self.advance_n(8)?);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter()
+                         .enumerate()
+                         .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - i * 8));
 
-            // get the data length amount as a u16.
-            let length = u16::from_be_bytes(self.advance_n(2)?
-                                                .try_into()
-                                                .unwrap());
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
 
-            let data = self.advance_n(length as usize)?;
+    encoded
+}
+
+/// Writes bytes as upper case hex, as used by SSHFP and TLSA presentation.
+fn fmt_hex(f: &mut std::fmt::Formatter<'_>, bytes: &[u8]) -> std::fmt::Result {
+    bytes.iter().try_for_each(|b| write!(f, "{:02X}", b))
+}
 
-            // combine the [name + type, class, ttl] + [len + data]
-            record_bytes.append(&mut [&length.to_be_bytes()[..], data].concat());
+/// Writes a character string in presentation format, quoted and escaped.
+fn fmt_character_string(f: &mut std::fmt::Formatter<'_>, string: &[u8]) -> std::fmt::Result {
+    write!(f, "\"")?;
 
-            records.push(Record::try_from(record_bytes.as_ref()).unwrap());
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+            0x20..=0x7E => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\{:03}", byte)?,
         }
+    }
 
-        Ok(records)
+    write!(f, "\"")
+}
+
+/// Presentation format of the data, unknown types use the RFC 3597 generic format.
+impl std::fmt::Display for RData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RData::A(address) => write!(f, "{}", address),
+            RData::Aaaa(address) => write!(f, "{}", address),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => write!(f, "{}", name),
+            RData::Mx { preference, exchange } => write!(f, "{} {}", preference, exchange),
+            RData::Txt(strings) => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    fmt_character_string(f, string)?;
+                }
+                Ok(())
+            },
+            RData::Soa { mname, rname, serial, refresh, retry, expire, minimum } => 
+                write!(f, "{} {} {} {} {} {} {}", 
+                    mname, rname, serial, refresh, retry, expire, minimum),
+            RData::Srv { priority, weight, port, target } => 
+                write!(f, "{} {} {} {}", priority, weight, port, target),
+            RData::Naptr { order, preference, flags, services, regexp, replacement } => {
+                write!(f, "{} {} ", order, preference)?;
+                for string in [flags, services, regexp] {
+                    fmt_character_string(f, string)?;
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", replacement)
+            },
+            RData::Sshfp { algorithm, fingerprint_type, fingerprint } => {
+                write!(f, "{} {} ", algorithm, fingerprint_type)?;
+                fmt_hex(f, fingerprint)
+            },
+            RData::Tlsa { usage, selector, matching_type, data } => {
+                write!(f, "{} {} {} ", usage, selector, matching_type)?;
+                fmt_hex(f, data)
+            },
+            RData::Svcb(binding) | RData::Https(binding) => {
+                write!(f, "{} {}", binding.priority, binding.target)?;
+                binding.params.iter().try_for_each(|p| write!(f, " {}", p))
+            },
+            RData::Caa { flags, tag, value } => {
+                write!(f, "{} {} ", flags, String::from_utf8_lossy(tag))?;
+                fmt_character_string(f, value)
+            },
+            RData::Unknown(_, data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                data.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            },
+        }
     }
+}
 
-    /// Parses packet bytes and turns them in a `DNSPacket`. 
-    pub fn deserialize(&mut self) -> Result<DNSPacket, String> {
-        /* Parse Header */
-        let header_bytes = self.advance_n(12)?;
+/// EDNS(0) information carried by the OPT pseudo-record (RFC 6891).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edns {
+    /// Largest UDP payload the sender can reassemble.
+    pub payload_size: u16,
+    /// Upper 8 bits of the 12 bit response code.
+    pub extended_rcode: u8,
+    /// Version of EDNS, only 0 exists.
+    pub version: u8,
+    /// DNSSEC OK flag.
+    pub dnssec_ok: bool,
+    /// The remaining flag bits, which are reserved.
+    pub z: u16,
+    /// Options in the order they were sent.
+    pub options: Vec<EdnsOption>,
+}
 
-        let (_, header) = Header::from_bytes((header_bytes.as_ref(), 0)).unwrap();
+/// A single option in the OPT record data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdnsOption {
+    /// Kind of option, see `option_code`.
+    pub code: u16,
+    /// Option data, without its code and length.
+    pub data: Vec<u8>,
+}
 
-        /* Parse Question Section */
-        let mut questions: Vec<Question> = Vec::with_capacity(header.qd_count as usize);
+impl Edns {
+    /// EDNS version 0 advertising `payload_size` bytes, without options.
+    pub fn new(payload_size: u16) -> Self {
+        Self { 
+            payload_size, 
+            extended_rcode: 0, 
+            version: 0, 
+            dnssec_ok: false, 
+            z: 0, 
+            options: Vec::new(),
+        }
+    }
+
+    /// Decodes an OPT record, its class and ttl fields are reused for EDNS data.
+    pub fn from_record(record: &Record) -> Result<Self, String> {
+        if !record.name.is_root() {
+            return Err(format!("OPT record with non-root owner {}.", record.name));
+        }
 
-        for _ in 0..questions.capacity() {
-            let mut question_bytes = self.parse_name()?;
+        let data = match &record.data {
+            RData::Unknown(record_type::OPT, data) => data,
+            _ => return Err("not an OPT record.".to_string()),
+        };
 
-            // concatenates the next 4 bytes after the name field onto the name bytes.
-            question_bytes.extend_from_slice(self.advance_n(4)?);
+        let mut options: Vec<EdnsOption> = Vec::new();
+        let mut position = 0;
 
-            questions.push(Question::try_from(question_bytes.as_ref()).unwrap());
+        while position < data.len() {
+            let header = data.get(position..position + 4).ok_or("truncated EDNS option.")?;
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let option = data.get(position + 4..position + 4 + length)
+                             .ok_or("truncated EDNS option.")?;
+
+            options.push(EdnsOption { code, data: option.to_vec() });
+            position += length + 4;
         }
 
-        /* Parse Answer Section */
-        let answers = self.parse_record(header.an_count as usize)?;
-        /* Parse Authority Section */
-        let authorities = self.parse_record(header.ns_count as usize)?;
-        /* Parse Additional Section */
-        let additionals = self.parse_record(header.ar_count as usize)?;
-        
-        Ok(DNSPacket::new(header, questions, answers, authorities, additionals))
+        let flags = record.ttl as u16;
+
+        Ok(Self {
+            payload_size: record.class,
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: flags & 0x8000 != 0,
+            z: flags & 0x7FFF,
+            options,
+        })
+    }
+
+    /// Encodes EDNS information as an OPT record.
+    pub fn to_record(&self) -> Record {
+        let data = self.options
+                       .iter()
+                       .flat_map(|o| {
+                           [&o.code.to_be_bytes()[..], 
+                            &(o.data.len() as u16).to_be_bytes()[..], 
+                            &o.data[..]].concat()
+                       })
+                       .collect();
+
+        let flags = if self.dnssec_ok { 0x8000 } else { 0 } | self.z & 0x7FFF;
+
+        Record {
+            name: DomainName::root(),
+            class: self.payload_size,
+            ttl: (self.extended_rcode as u32) << 24 | (self.version as u32) << 16 | flags as u32,
+            data: RData::Unknown(record_type::OPT, data),
+        }
+    }
+
+    /// Returns the first option with the given code.
+    pub fn option(&self, code: u16) -> Option<&EdnsOption> {
+        self.options.iter().find(|o| o.code == code)
+    }
+
+    /// Removes every option with the given code.
+    pub fn remove_option(&mut self, code: u16) {
+        self.options.retain(|o| o.code != code);
+    }
+
+    /// Replaces any options with the same code as `option` with it.
+    pub fn set_option(&mut self, option: EdnsOption) {
+        self.remove_option(option.code);
+        self.options.push(option);
+    }
+}
+
+/// EDNS option codes.
+pub mod option_code {
+    /// EDNS Client Subnet (RFC 7871).
+    pub const CLIENT_SUBNET: u16 = 8;
+}
+
+/// The EDNS Client Subnet option (RFC 7871).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSubnet {
+    /// The client network, with the bits past `source_prefix` cleared.
+    pub address: IpAddr,
+    /// Leading bits of `address` that are sent.
+    pub source_prefix: u8,
+    /// Prefix the answer is valid for, set by the responding server.
+    pub scope_prefix: u8,
+}
+
+/// Clears the bits of `address` past `prefix`.
+pub fn mask_address(address: IpAddr, prefix: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - prefix.min(32) as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        },
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128) as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        },
+    }
+}
+
+/// Parses a list of `<address>[,<address>...]` into its IPv4 and IPv6 addresses, errors call
+/// them `what` addresses.
+pub fn parse_addresses(s: &str, what: &str) -> Result<(Vec<Ipv4Addr>, Vec<Ipv6Addr>), String> {
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+
+    for address in s.split(',') {
+        match address.parse() {
+            Ok(IpAddr::V4(a)) => v4.push(a),
+            Ok(IpAddr::V6(a)) => v6.push(a),
+            Err(_) => return Err(format!("invalid {} address `{}`", what, address)),
+        }
+    }
+
+    Ok((v4, v6))
+}
+
+impl ClientSubnet {
+    /// Creates the option for the network of `address` with `source_prefix` bits.
+    pub fn new(address: IpAddr, source_prefix: u8) -> Self {
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let source_prefix = source_prefix.min(max_prefix);
+
+        Self { address: mask_address(address, source_prefix), source_prefix, scope_prefix: 0 }
+    }
+
+    /// Decodes the option, which must have the client subnet code.
+    pub fn from_option(option: &EdnsOption) -> Result<Self, String> {
+        let data = &option.data;
+
+        if option.code != option_code::CLIENT_SUBNET || data.len() < 4 {
+            return Err("malformed client subnet option.".to_string());
+        }
+
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let (source_prefix, scope_prefix) = (data[2], data[3]);
+        let address = &data[4..];
+
+        // only as many address bytes as the source prefix needs are sent.
+        if address.len() != (source_prefix as usize).div_ceil(8) {
+            return Err("client subnet address doesn't match its prefix.".to_string());
+        }
+
+        let address = match family {
+            1 if source_prefix <= 32 => {
+                let mut octets = [0; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            },
+            2 if source_prefix <= 128 => {
+                let mut octets = [0; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            },
+            _ => return Err(format!("unsupported client subnet family {}.", family)),
+        };
+
+        Ok(Self { address, source_prefix, scope_prefix })
+    }
+
+    /// Encodes the option, with only as many address bytes as the source prefix needs.
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets): (u16, Vec<u8>) = match self.address {
+            IpAddr::V4(v4) => (1, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (2, v6.octets().to_vec()),
+        };
+
+        let mut data = family.to_be_bytes().to_vec();
+        data.extend_from_slice(&[self.source_prefix, self.scope_prefix]);
+        data.extend_from_slice(&octets[..(self.source_prefix as usize).div_ceil(8)]);
+
+        EdnsOption { code: option_code::CLIENT_SUBNET, data }
+    }
+}
+
+/// Why a message couldn't be parsed, along with the offset the problem was found at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The message ended while `needed` more bytes were expected at `offset`.
+    Truncated {
+        /// Where the missing bytes should start.
+        offset: usize,
+        /// How many bytes are missing.
+        needed: usize,
+    },
+    /// A label starts with a length byte of a type we don't support.
+    BadLabel {
+        /// Where the length byte is.
+        offset: usize,
+        /// The length byte.
+        byte: u8,
+    },
+    /// The name at `offset` expands to more than 255 bytes.
+    NameTooLong {
+        /// Where the name starts.
+        offset: usize,
+    },
+    /// Following the compression pointer at `offset` leads back to a pointer already followed.
+    PointerLoop {
+        /// Where the pointer is.
+        offset: usize,
+    },
+    /// The record data at `offset` doesn't take up the `length` bytes it claims.
+    BadRdataLength {
+        /// Where the record data starts.
+        offset: usize,
+        /// Type of the record.
+        ty: u16,
+        /// Length the record claims.
+        length: usize,
+    },
+    /// The record data at `offset` takes up its length, but its content is invalid.
+    BadRdata {
+        /// Where the invalid part starts.
+        offset: usize,
+        /// What's invalid about it.
+        reason: String,
+    },
+    /// The OPT record at `offset` is malformed, or not the only one.
+    BadOpt {
+        /// Where the OPT record starts.
+        offset: usize,
+        /// What's wrong with it.
+        reason: String,
+    },
+    /// The message ended before the amount of entries the header announced for a section.
+    CountMismatch {
+        /// Name of the section.
+        section: &'static str,
+        /// Entries the header announced.
+        expected: usize,
+        /// Entries that were there.
+        found: usize,
+    },
+    /// Bytes are left over at `offset` after every section was parsed.
+    TrailingBytes {
+        /// Where the first extra byte is.
+        offset: usize,
+        /// How many bytes are left.
+        count: usize,
+    },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Truncated { offset, needed } => 
+                write!(f, "message truncated, {} more bytes needed at offset {}.", needed, offset),
+            ParseError::BadLabel { offset, byte } => 
+                write!(f, "unsupported label type {:#04x} at offset {}.", byte, offset),
+            ParseError::NameTooLong { offset } => 
+                write!(f, "name at offset {} is longer than {} bytes.", offset, MAX_NAME_LENGTH),
+            ParseError::PointerLoop { offset } => 
+                write!(f, "compression pointer loop at offset {}.", offset),
+            ParseError::BadRdataLength { offset, ty, length } => 
+                write!(f, "type {} record data at offset {} doesn't match its length {}.", 
+                    ty, offset, length),
+            ParseError::BadRdata { offset, reason } => 
+                write!(f, "invalid record data at offset {}: {}", offset, reason),
+            ParseError::BadOpt { offset, reason } => 
+                write!(f, "invalid OPT record at offset {}: {}", offset, reason),
+            ParseError::CountMismatch { section, expected, found } => 
+                write!(f, "{} section has {} entries, header announced {}.", section, found, expected),
+            ParseError::TrailingBytes { offset, count } => 
+                write!(f, "{} trailing bytes at offset {}.", count, offset),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    /// Offset in the message the error was found at, count mismatches only happen at its end.
+    #[cfg(feature = "error-context")]
+    fn offset(&self) -> Option<usize> {
+        match self {
+            ParseError::Truncated { offset, .. }
+            | ParseError::BadLabel { offset, .. }
+            | ParseError::NameTooLong { offset }
+            | ParseError::PointerLoop { offset }
+            | ParseError::BadRdataLength { offset, .. }
+            | ParseError::BadRdata { offset, .. }
+            | ParseError::BadOpt { offset, .. }
+            | ParseError::TrailingBytes { offset, .. } => Some(*offset),
+            ParseError::CountMismatch { .. } => None,
+        }
+    }
+
+    /// Describes the error for logs, along with a dump of `message` around the offending byte
+    /// when built with the `error-context` feature.
+    #[cfg(feature = "error-context")]
+    pub fn with_context(&self, message: &[u8]) -> String {
+        use std::fmt::Write;
+
+        let offset = self.offset().unwrap_or(message.len());
+        let mut text = self.to_string();
+
+        // the row of the offending byte, with one row on either side.
+        let rows = message.chunks(16).enumerate().skip((offset / 16).saturating_sub(1)).take(3);
+
+        for (row, bytes) in rows {
+            write!(text, "\n  {:04x} ", row * 16).unwrap();
+
+            for (i, byte) in bytes.iter().enumerate() {
+                match row * 16 + i == offset {
+                    true => write!(text, "[{:02x}]", byte).unwrap(),
+                    false => write!(text, " {:02x} ", byte).unwrap(),
+                }
+            }
+        }
+
+        text
+    }
+
+    /// Describes the error for logs, `message` is only dumped with the `error-context` feature.
+    #[cfg(not(feature = "error-context"))]
+    pub fn with_context(&self, _message: &[u8]) -> String {
+        self.to_string()
+    }
+}
+
+/// Smallest amount of bytes a question takes up, with a root name.
+const MIN_QUESTION_LENGTH: usize = 5;
+
+/// Smallest amount of bytes a record takes up, with a root name and no data.
+const MIN_RECORD_LENGTH: usize = 11;
+
+/// Parses a message out of its wire format.
+///
+/// Nothing the buffer holds makes it panic, malformed messages are reported as `ParseError`.
+pub struct PacketParser<'a> {
+    /// A buffer that *should* contain a DNS packet.
+    buffer: &'a [u8],
+    /// A pointer to an unparsed packet position.
+    current: usize,
+    /// Holds offsets of already parsed labels that map to their decompressed names.
+    decompress_map: HashMap<usize, Vec<u8>>,
+}
+
+impl<'a> PacketParser<'a> {
+    /// Creates a parser for the message in `buffer`, which must hold nothing else.
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, current: 0, decompress_map: HashMap::new() }
+    } 
+ 
+    /// Returns byte at `offset`, fails if it lies outside of the buffer.
+    fn get_byte_at(&self, offset: usize) -> Result<u8, ParseError> {
+        self.buffer.get(offset).copied().ok_or(ParseError::Truncated { offset, needed: 1 })
+    }
+
+    /// Bytes left after the position pointer.
+    fn remaining(&self) -> usize {
+        self.buffer.len().saturating_sub(self.current)
+    }
+
+    /// Gets range of bytes starting from `current` to `n`.
+    ///
+    /// Makes sure it doesn't overstep its bounds out of the buffer.
+    fn advance_n(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
+        match self.buffer.get(self.current..self.current.saturating_add(n)) {
+            None => Err(ParseError::Truncated { 
+                offset: self.current, 
+                needed: n - self.remaining(),
+            }),
+            Some(bytes) => { 
+                self.current += n; 
+                Ok(bytes) 
+            },
+        } 
+    }
+
+    /// Parses `N` bytes into an array and increments the position pointer past them.
+    fn parse_array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.advance_n(N)?);
+        Ok(array)
+    }
+    
+    /// Parses variable length name field from bytes.
+    ///
+    /// Increments position pointer by the bytes the name occupies in place and returns the
+    /// fully expanded name.
+    fn parse_name(&mut self) -> Result<DomainName, ParseError> {
+        let offset = self.current;
+        let (name, length) = self.expand_name(offset)?;
+        self.advance_n(length)?;
+        // the expanded name was checked label by label already, this can't really fail.
+        DomainName::from_wire(&name).map_err(|_| ParseError::NameTooLong { offset })
+    }
+
+    /// Parses a big endian `u8` and increments the position pointer past it.
+    fn parse_u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.advance_n(1)?[0])
+    }
+
+    /// Parses a length prefixed character string and increments the position pointer past it.
+    fn parse_character_string(&mut self) -> Result<Vec<u8>, ParseError> {
+        let length = self.parse_u8()? as usize;
+        Ok(self.advance_n(length)?.to_vec())
+    }
+
+    /// Parses the bytes left until `end` and increments the position pointer to it.
+    fn parse_remaining(&mut self, end: usize) -> Result<Vec<u8>, ParseError> {
+        Ok(self.advance_n(end.saturating_sub(self.current))?.to_vec())
+    }
+
+    /// Parses a big endian `u16` and increments the position pointer past it.
+    fn parse_u16(&mut self) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.parse_array()?))
+    }
+
+    /// Parses a big endian `u32` and increments the position pointer past it.
+    fn parse_u32(&mut self) -> Result<u32, ParseError> {
+        Ok(u32::from_be_bytes(self.parse_array()?))
+    }
+
+    /// Expands the name at `offset`, following any compression pointers (RFC 1035 4.1.4).
+    ///
+    /// Returns the uncompressed name bytes and the amount of bytes the name occupies at
+    /// `offset`, which stops after the first pointer.
+    fn expand_name(&mut self, offset: usize) -> Result<(Vec<u8>, usize), ParseError> {
+        let mut name: Vec<u8> = Vec::new();
+        let mut position = offset;
+        // bytes taken up at `offset`, known once we hit the first pointer or the root label.
+        let mut length: Option<usize> = None;
+        // every offset we jumped to, jumping to one twice means the pointers loop.
+        let mut jumps: HashSet<usize> = HashSet::new();
+        // label offsets that were read in place, paired with where they begin in `name`.
+        let mut labels: Vec<(usize, usize)> = Vec::new();
+
+        loop {
+            let byte = self.get_byte_at(position)?;
+
+            match byte & 0xC0 {
+                0xC0 => {
+                    let pointer = u16::from_be_bytes([byte, self.get_byte_at(position + 1)?]) 
+                        & 0x3FFF;
+                    let pointer = pointer as usize;
+
+                    length.get_or_insert_with(|| position + 2 - offset);
+
+                    if !jumps.insert(pointer) {
+                        return Err(ParseError::PointerLoop { offset: position });
+                    }
+
+                    // the rest of the name was already expanded once, reuse it.
+                    if let Some(suffix) = self.decompress_map.get(&pointer) {
+                        name.extend_from_slice(suffix);
+                        break;
+                    }
+
+                    position = pointer;
+                },
+                0x00 => {
+                    let label_length = byte as usize;
+
+                    labels.push((position, name.len()));
+
+                    for i in 0..=label_length {
+                        name.push(self.get_byte_at(position + i)?);
+                    }
+
+                    if label_length == 0 {
+                        length.get_or_insert_with(|| position + 1 - offset);
+                        break;
+                    }
+
+                    position += label_length + 1;
+                },
+                _ => return Err(ParseError::BadLabel { offset: position, byte }),
+            }
+
+            if name.len() > MAX_NAME_LENGTH {
+                return Err(ParseError::NameTooLong { offset });
+            }
+        }
+
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(ParseError::NameTooLong { offset });
+        }
+
+        // remember every suffix we read so later pointers to them don't walk the buffer again.
+        for (label_offset, start) in labels {
+            self.decompress_map
+                .entry(label_offset)
+                .or_insert_with(|| name[start..].to_vec());
+        }
+
+        Ok((name, length.unwrap_or_default()))
+    }
+
+    /// Fails with a count mismatch if the message ends before entry `found` of `section`.
+    fn check_count(&self, section: &'static str, expected: usize, found: usize) 
+        -> Result<(), ParseError> {
+        match self.remaining() {
+            0 => Err(ParseError::CountMismatch { section, expected, found }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses `record_count` records of `section`, returning them with the offset of any OPT
+    /// record among them.
+    fn parse_record(&mut self, section: &'static str, record_count: usize) 
+        -> Result<(Vec<Record>, Option<usize>), ParseError> {
+        // counts come from the header, don't let a bogus one allocate more than could fit.
+        let mut records: Vec<Record> = 
+            Vec::with_capacity(record_count.min(self.remaining() / MIN_RECORD_LENGTH));
+        let mut opt_offset: Option<usize> = None;
+
+        for _ in 0..record_count {
+            self.check_count(section, record_count, records.len())?;
+
+            let offset = self.current;
+            let name = self.parse_name()?;
+            let ty = self.parse_u16()?;
+            let class = self.parse_u16()?;
+            let ttl = self.parse_u32()?;
+
+            // get the data length amount as a u16.
+            let length = self.parse_u16()?;
+
+            let data = self.parse_rdata(ty, length as usize)?;
+
+            if ty == record_type::OPT {
+                opt_offset.get_or_insert(offset);
+            }
+
+            records.push(Record { name, class, ttl, data });
+        }
+
+        Ok((records, opt_offset))
+    }
+
+    /// Parses `length` bytes of record data of type `ty`, expanding any names inside of it.
+    fn parse_rdata(&mut self, ty: u16, length: usize) -> Result<RData, ParseError> {
+        let start = self.current;
+        let end = start + length;
+        let bad_length = ParseError::BadRdataLength { offset: start, ty, length };
+
+        // names may point outside of the data, but nothing else may run past its end.
+        if end > self.buffer.len() {
+            return Err(ParseError::Truncated { offset: start, needed: end - self.buffer.len() });
+        }
+
+        let data = match ty {
+            record_type::A if length == 4 => RData::A(Ipv4Addr::from(self.parse_array()?)),
+            record_type::AAAA if length == 16 => RData::Aaaa(Ipv6Addr::from(self.parse_array()?)),
+            record_type::CNAME => RData::Cname(self.parse_name()?),
+            record_type::NS => RData::Ns(self.parse_name()?),
+            record_type::PTR => RData::Ptr(self.parse_name()?),
+            record_type::MX => RData::Mx { 
+                preference: self.parse_u16()?, 
+                exchange: self.parse_name()?,
+            },
+            record_type::TXT => {
+                let mut strings: Vec<Vec<u8>> = Vec::new();
+
+                while self.current < end {
+                    strings.push(self.parse_character_string()?);
+                }
+
+                RData::Txt(strings)
+            },
+            record_type::SOA => RData::Soa {
+                mname: self.parse_name()?,
+                rname: self.parse_name()?,
+                serial: self.parse_u32()?,
+                refresh: self.parse_u32()?,
+                retry: self.parse_u32()?,
+                expire: self.parse_u32()?,
+                minimum: self.parse_u32()?,
+            },
+            record_type::SRV => RData::Srv {
+                priority: self.parse_u16()?,
+                weight: self.parse_u16()?,
+                port: self.parse_u16()?,
+                target: self.parse_name()?,
+            },
+            record_type::NAPTR => RData::Naptr {
+                order: self.parse_u16()?,
+                preference: self.parse_u16()?,
+                flags: self.parse_character_string()?,
+                services: self.parse_character_string()?,
+                regexp: self.parse_character_string()?,
+                replacement: self.parse_name()?,
+            },
+            record_type::SSHFP => RData::Sshfp {
+                algorithm: self.parse_u8()?,
+                fingerprint_type: self.parse_u8()?,
+                fingerprint: self.parse_remaining(end)?,
+            },
+            record_type::TLSA => RData::Tlsa {
+                usage: self.parse_u8()?,
+                selector: self.parse_u8()?,
+                matching_type: self.parse_u8()?,
+                data: self.parse_remaining(end)?,
+            },
+            record_type::SVCB => RData::Svcb(self.parse_service_binding(end)?),
+            record_type::HTTPS => RData::Https(self.parse_service_binding(end)?),
+            record_type::CAA => RData::Caa {
+                flags: self.parse_u8()?,
+                tag: self.parse_character_string()?,
+                value: self.parse_remaining(end)?,
+            },
+            record_type::A | record_type::AAAA => return Err(bad_length),
+            _ => RData::Unknown(ty, self.advance_n(length)?.to_vec()),
+        };
+
+        if self.current != end {
+            return Err(bad_length);
+        }
+
+        Ok(data)
+    }
+
+    /// Parses SVCB or HTTPS record data ending at `end`.
+    fn parse_service_binding(&mut self, end: usize) -> Result<ServiceBinding, ParseError> {
+        let priority = self.parse_u16()?;
+        let target = self.parse_name()?;
+        let mut params: Vec<SvcParam> = Vec::new();
+
+        while self.current < end {
+            let offset = self.current;
+            let key = self.parse_u16()?;
+            let length = self.parse_u16()? as usize;
+            let value = self.advance_n(length)?;
+
+            // keys are strictly increasing, so none repeats either (RFC 9460 2.2).
+            if params.last().is_some_and(|p| p.key() >= key) {
+                let reason = format!("svc param key{} out of order or repeated.", key);
+                return Err(ParseError::BadRdata { offset, reason });
+            }
+
+            params.push(SvcParam::from_wire(key, value)
+                .map_err(|reason| ParseError::BadRdata { offset, reason })?);
+        }
+
+        Ok(ServiceBinding { priority, target, params })
+    }
+
+    /// Parses packet bytes and turns them in a `DNSPacket`. 
+    ///
+    /// Every section has to hold as many entries as the header announced, and nothing may
+    /// follow the last one.
+    pub fn deserialize(&mut self) -> Result<DNSPacket, ParseError> {
+        /* Parse Header */
+        let header = Header::from_wire(&self.parse_array()?);
+
+        /* Parse Question Section */
+        let question_count = header.qd_count as usize;
+        let mut questions: Vec<Question> = 
+            Vec::with_capacity(question_count.min(self.remaining() / MIN_QUESTION_LENGTH));
+
+        for _ in 0..question_count {
+            self.check_count("question", question_count, questions.len())?;
+
+            let name = self.parse_name()?;
+            let ty = self.parse_u16()?;
+            let class = self.parse_u16()?;
+
+            questions.push(Question { name, ty, class });
+        }
+
+        /* Parse Answer Section */
+        let (answers, _) = self.parse_record("answer", header.an_count as usize)?;
+        /* Parse Authority Section */
+        let (authorities, _) = self.parse_record("authority", header.ns_count as usize)?;
+        /* Parse Additional Section */
+        let (mut additionals, opt_offset) = 
+            self.parse_record("additional", header.ar_count as usize)?;
+
+        if self.current < self.buffer.len() {
+            let (offset, count) = (self.current, self.remaining());
+            return Err(ParseError::TrailingBytes { offset, count });
+        }
+
+        /* Pull Out EDNS */
+        let mut opt_records = additionals.iter().filter(|r| r.ty() == record_type::OPT);
+        let bad_opt = |reason| ParseError::BadOpt { offset: opt_offset.unwrap_or_default(), reason };
+
+        let edns = match (opt_records.next(), opt_records.next()) {
+            (None, _) => None,
+            (Some(record), None) => Some(Edns::from_record(record).map_err(bad_opt)?),
+            (Some(_), Some(_)) => return Err(bad_opt("more than one OPT record.".to_string())),
+        };
+
+        additionals.retain(|r| r.ty() != record_type::OPT);
+        
+        Ok(DNSPacket::new(header, questions, answers, authorities, additionals, edns))
+    }
+}
+
+/// Writes a message in its wire format, compressing names.
+pub struct PacketSerializer {
+    /// The packet bytes written so far.
+    buffer: Vec<u8>,
+    /// Maps uncompressed name suffixes to the offset they were first written at.
+    compress_map: HashMap<Vec<u8>, usize>,
+}
+
+impl PacketSerializer {
+    /// Creates a serializer with an empty buffer.
+    pub fn new() -> Self {
+        Self { buffer: Vec::with_capacity(512), compress_map: HashMap::new() }
+    }
+
+    /// Writes a name, replacing the longest suffix already in the packet with a pointer.
+    fn write_name(&mut self, name: &DomainName) {
+        let name = name.to_wire();
+        let mut position = 0;
+
+        while position < name.len() && name[position] != 0 {
+            let suffix = &name[position..];
+
+            if let Some(&offset) = self.compress_map.get(suffix) {
+                self.buffer.extend_from_slice(&(0xC000 | offset as u16).to_be_bytes());
+                return;
+            }
+
+            if self.buffer.len() <= MAX_POINTER_OFFSET {
+                self.compress_map.insert(suffix.to_vec(), self.buffer.len());
+            }
+
+            let label_length = name[position] as usize + 1;
+            self.buffer.extend_from_slice(&name[position..position + label_length]);
+            position += label_length;
+        }
+
+        // root label.
+        self.buffer.push(0);
+    }
+
+    fn write_question(&mut self, question: &Question) {
+        self.write_name(&question.name);
+        self.buffer.extend_from_slice(&question.ty.to_be_bytes());
+        self.buffer.extend_from_slice(&question.class.to_be_bytes());
+    }
+
+    /// Writes a name without compressing it or making it a compression target.
+    fn write_uncompressed_name(&mut self, name: &DomainName) {
+        self.buffer.extend_from_slice(&name.to_wire());
+    }
+
+    fn write_character_string(&mut self, string: &[u8]) {
+        self.buffer.push(string.len() as u8);
+        self.buffer.extend_from_slice(string);
+    }
+
+    fn write_record(&mut self, record: &Record) {
+        self.write_name(&record.name);
+        self.buffer.extend_from_slice(&record.ty().to_be_bytes());
+        self.buffer.extend_from_slice(&record.class.to_be_bytes());
+        self.buffer.extend_from_slice(&record.ttl.to_be_bytes());
+
+        // data length isn't known until the names in it have been compressed.
+        let length_offset = self.buffer.len();
+        self.buffer.extend_from_slice(&[0, 0]);
+
+        self.write_rdata(&record.data);
+
+        let length = (self.buffer.len() - length_offset - 2) as u16;
+        self.buffer[length_offset..length_offset + 2].copy_from_slice(&length.to_be_bytes());
+    }
+
+    /// Writes record data, only compressing names of the RFC 1035 types (RFC 3597 4).
+    fn write_rdata(&mut self, data: &RData) {
+        match data {
+            RData::A(address) => self.buffer.extend_from_slice(&address.octets()),
+            RData::Aaaa(address) => self.buffer.extend_from_slice(&address.octets()),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => self.write_name(name),
+            RData::Mx { preference, exchange } => {
+                self.buffer.extend_from_slice(&preference.to_be_bytes());
+                self.write_name(exchange);
+            },
+            RData::Txt(strings) => {
+                for string in strings {
+                    self.write_character_string(string);
+                }
+            },
+            RData::Soa { mname, rname, serial, refresh, retry, expire, minimum } => {
+                self.write_name(mname);
+                self.write_name(rname);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    self.buffer.extend_from_slice(&value.to_be_bytes());
+                }
+            },
+            RData::Srv { priority, weight, port, target } => {
+                for value in [priority, weight, port] {
+                    self.buffer.extend_from_slice(&value.to_be_bytes());
+                }
+                self.write_uncompressed_name(target);
+            },
+            RData::Naptr { order, preference, flags, services, regexp, replacement } => {
+                self.buffer.extend_from_slice(&order.to_be_bytes());
+                self.buffer.extend_from_slice(&preference.to_be_bytes());
+                for string in [flags, services, regexp] {
+                    self.write_character_string(string);
+                }
+                self.write_uncompressed_name(replacement);
+            },
+            RData::Sshfp { algorithm, fingerprint_type, fingerprint } => {
+                self.buffer.extend_from_slice(&[*algorithm, *fingerprint_type]);
+                self.buffer.extend_from_slice(fingerprint);
+            },
+            RData::Tlsa { usage, selector, matching_type, data } => {
+                self.buffer.extend_from_slice(&[*usage, *selector, *matching_type]);
+                self.buffer.extend_from_slice(data);
+            },
+            RData::Svcb(binding) | RData::Https(binding) => {
+                self.buffer.extend_from_slice(&binding.priority.to_be_bytes());
+                self.write_uncompressed_name(&binding.target);
+
+                // keys have to be in increasing order on the wire.
+                let mut params: Vec<&SvcParam> = binding.params.iter().collect();
+                params.sort_by_key(|p| p.key());
+
+                for param in params {
+                    let value = param.to_wire();
+                    self.buffer.extend_from_slice(&param.key().to_be_bytes());
+                    self.buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                    self.buffer.extend_from_slice(&value);
+                }
+            },
+            RData::Caa { flags, tag, value } => {
+                self.buffer.push(*flags);
+                self.write_character_string(tag);
+                self.buffer.extend_from_slice(value);
+            },
+            RData::Unknown(_, data) => self.buffer.extend_from_slice(data),
+        }
+    }
+
+    /// Turns a `DNSPacket` into bytes, consuming the serializer.
+    pub fn serialize(mut self, packet: &DNSPacket) -> Vec<u8> {
+        self.buffer.extend_from_slice(&packet.header.to_wire());
+
+        // keep the counts in sync with the sections, they may have been modified.
+        let counts = [packet.questions.len(), packet.answers.len(), 
+            packet.authorities.len(), packet.additionals.len() + packet.edns.is_some() as usize];
+
+        for (i, count) in counts.iter().enumerate() {
+            self.buffer[4 + i * 2..6 + i * 2].copy_from_slice(&(*count as u16).to_be_bytes());
+        }
+
+        packet.questions.iter().for_each(|q| self.write_question(q));
+        packet.answers.iter().for_each(|r| self.write_record(r));
+        packet.authorities.iter().for_each(|r| self.write_record(r));
+        packet.additionals.iter().for_each(|r| self.write_record(r));
+
+        if let Some(edns) = &packet.edns {
+            self.write_record(&edns.to_record());
+        }
+
+        self.buffer
+    }
+}
+
+impl Default for PacketSerializer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::MessageBuilder;
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn record(owner: &str, data: RData) -> Record {
+        Record { name: name(owner), class: record_class::IN, ttl: 300, data }
+    }
+
+    /// A query header with id 0x1234, recursion desired and the given section counts.
+    fn header(qd_count: u16, an_count: u16, ns_count: u16, ar_count: u16) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34, 0x01, 0x00];
+
+        for count in [qd_count, an_count, ns_count, ar_count] {
+            bytes.extend_from_slice(&count.to_be_bytes());
+        }
+
+        bytes
+    }
+
+    /// Appends the type A, class IN fields of a question.
+    fn question_fields(bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&[0, 1, 0, 1]);
+    }
+
+    fn parse(bytes: &[u8]) -> Result<DNSPacket, ParseError> {
+        PacketParser::new(bytes).deserialize()
+    }
+
+    #[test]
+    fn names_compare_case_insensitively() {
+        let names: std::collections::HashSet<DomainName> =
+            ["WWW.Example.COM", "www.example.com.", "wWw.eXaMpLe.CoM"].map(name).into();
+
+        assert_eq!(names.len(), 1);
+        assert_ne!(name("www.example.com"), name("www.example.org"));
+        assert_ne!(name("www.example.com"), name("example.com"));
+
+        // case is kept, only ignored when comparing.
+        assert_eq!(name("WWW.Example.COM").to_string(), "WWW.Example.COM");
+    }
+
+    #[test]
+    fn name_presentation() {
+        let labels = vec![b"a.b\\c".to_vec(), vec![b' ', 0, 0xFF, b'~'], b"com".to_vec()];
+        let escaped = DomainName::from_labels(labels).unwrap();
+
+        assert_eq!(escaped.to_string(), r"a\.b\\c.\032\000\255~.com");
+        assert_eq!(escaped.to_string().parse::<DomainName>().unwrap().to_wire(),
+                   escaped.to_wire());
+
+        assert_eq!(name(r"\065\.\066.com").to_wire(), b"\x03A.B\x03com\x00");
+        assert_eq!(name(".").to_string(), ".");
+
+        for invalid in [r"a\256.com", r"a\1", "a\\", "a..com", ".com"] {
+            assert!(invalid.parse::<DomainName>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn name_limits() {
+        let label = |length| "a".repeat(length);
+
+        assert_eq!(name(&label(63)).wire_len(), 65);
+        assert!(label(64).parse::<DomainName>().is_err());
+
+        // three labels of 63 bytes and one of 61 make 255 bytes with their lengths and the root.
+        let longest = [label(63), label(63), label(63), label(61)].join(".");
+        assert_eq!(name(&longest).wire_len(), 255);
+        assert!(format!("a{}", longest).parse::<DomainName>().is_err());
+        assert!(DomainName::from_labels(vec![vec![b'a'; 63]; 4]).is_err());
+    }
+
+    #[test]
+    fn pointer_after_labels() {
+        let mut bytes = header(3, 0, 0, 0);
+
+        // example.com at 12, its com label at 20.
+        bytes.extend_from_slice(b"\x07example\x03com\x00");
+        question_fields(&mut bytes);
+        bytes.extend_from_slice(b"\x03www\xC0\x0C");
+        question_fields(&mut bytes);
+        bytes.extend_from_slice(b"\x04mail\x02eu\xC0\x14");
+        question_fields(&mut bytes);
+
+        let packet = parse(&bytes).unwrap();
+        let names: Vec<&DomainName> = packet.questions.iter().map(|q| &q.name).collect();
+
+        assert_eq!(names, [&name("example.com"), &name("www.example.com"), &name("mail.eu.com")]);
+    }
+
+    #[test]
+    fn pointer_loops() {
+        // a pointer to itself.
+        let mut bytes = header(1, 0, 0, 0);
+        bytes.extend_from_slice(&[0xC0, 0x0C]);
+        question_fields(&mut bytes);
+
+        assert!(matches!(parse(&bytes), Err(ParseError::PointerLoop { .. })));
+
+        // two pointers to each other, after a label.
+        let mut bytes = header(1, 0, 0, 0);
+        bytes.extend_from_slice(b"\x01a\xC0\x10\xC0\x0E");
+        question_fields(&mut bytes);
+
+        assert!(matches!(parse(&bytes), Err(ParseError::PointerLoop { .. })));
+    }
+
+    #[test]
+    fn name_too_long() {
+        let mut bytes = header(1, 0, 0, 0);
+
+        // four 63 byte labels and a 3 byte one make 260 bytes with the root label.
+        for _ in 0..4 {
+            bytes.push(63);
+            bytes.extend_from_slice(&[b'a'; 63]);
+        }
+        bytes.extend_from_slice(b"\x03com\x00");
+        question_fields(&mut bytes);
+
+        assert_eq!(parse(&bytes).unwrap_err(), ParseError::NameTooLong { offset: 12 });
+
+        // the same through pointers, each part fitting on its own.
+        let mut bytes = header(2, 0, 0, 0);
+        for _ in 0..3 {
+            bytes.push(63);
+            bytes.extend_from_slice(&[b'a'; 63]);
+        }
+        bytes.push(0);
+        question_fields(&mut bytes);
+        for _ in 0..2 {
+            bytes.push(63);
+            bytes.extend_from_slice(&[b'b'; 63]);
+        }
+        bytes.extend_from_slice(&[0xC0, 0x0C]);
+        question_fields(&mut bytes);
+
+        assert!(matches!(parse(&bytes), Err(ParseError::NameTooLong { .. })));
+    }
+
+    #[test]
+    fn count_mismatch_and_trailing_bytes() {
+        let mut bytes = header(2, 0, 0, 0);
+        bytes.extend_from_slice(b"\x07example\x03com\x00");
+        question_fields(&mut bytes);
+
+        assert_eq!(parse(&bytes).unwrap_err(), 
+                   ParseError::CountMismatch { section: "question", expected: 2, found: 1 });
+
+        let mut bytes = header(1, 0, 0, 1);
+        bytes.extend_from_slice(b"\x07example\x03com\x00");
+        question_fields(&mut bytes);
+
+        assert_eq!(parse(&bytes).unwrap_err(), 
+                   ParseError::CountMismatch { section: "additional", expected: 1, found: 0 });
+
+        let mut bytes = header(1, 0, 0, 0);
+        bytes.extend_from_slice(b"\x07example\x03com\x00");
+        question_fields(&mut bytes);
+        let length = bytes.len();
+        bytes.extend_from_slice(&[0, 0, 0]);
+
+        assert_eq!(parse(&bytes).unwrap_err(), 
+                   ParseError::TrailingBytes { offset: length, count: 3 });
+    }
+
+    #[test]
+    fn format_error() {
+        let mut bytes = header(2, 0, 0, 0);
+        bytes.extend_from_slice(b"\x07example\x03com\x00");
+        question_fields(&mut bytes);
+        assert!(parse(&bytes).is_err());
+
+        let response = DNSPacket::format_error(&bytes).unwrap();
+        let response = parse(&response.serialize()).unwrap();
+
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(response.header.qr, 1);
+        assert_eq!(response.header.rd, 1);
+        assert_eq!(response.rcode(), rcode::FORMERR);
+        assert!(response.questions.is_empty());
+
+        // responses and messages without a whole header are left unanswered.
+        bytes[2] |= 0x80;
+        assert!(DNSPacket::format_error(&bytes).is_none());
+        assert!(DNSPacket::format_error(&bytes[..HEADER_LENGTH - 1]).is_none());
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut query = MessageBuilder::query(name("example.com"), record_type::A)
+            .edns(EDNS_PAYLOAD_SIZE, false)
+            .build();
+        query.edns.as_mut().unwrap().version = 1;
+
+        let response = PacketParser::new(&query.bad_version().serialize()).deserialize().unwrap();
+        let edns = response.edns.as_ref().unwrap();
+
+        assert_eq!(response.rcode(), rcode::BADVERS);
+        assert_eq!((response.header.r_code, edns.extended_rcode, edns.version), (0, 1, 0));
+        assert_eq!(response.questions, query.questions);
+    }
+
+    #[test]
+    fn round_trip() {
+        let query = MessageBuilder::query(name("www.example.com"), record_type::A)
+            .edns(EDNS_PAYLOAD_SIZE, true)
+            .build();
+
+        let mut packet = query.reply();
+        packet.answers = vec![
+            record("www.example.com", RData::Cname(name("example.com"))),
+            record("example.com", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+        ];
+        packet.authorities = vec![
+            record("example.com", RData::Ns(name("ns.example.com"))),
+        ];
+        packet.additionals = vec![
+            record("ns.example.com", RData::Aaaa("2001:db8::53".parse().unwrap())),
+            record("example.com", RData::Txt(vec![b"v=spf1 -all".to_vec()])),
+            record("example.com", RData::Unknown(99, vec![1, 2, 3])),
+        ];
+
+        let bytes = packet.serialize();
+        let parsed = parse(&bytes).unwrap();
+
+        assert_eq!(parsed.questions, packet.questions);
+        assert_eq!(parsed.answers, packet.answers);
+        assert_eq!(parsed.authorities, packet.authorities);
+        assert_eq!(parsed.additionals, packet.additionals);
+        assert_eq!(parsed.edns, packet.edns);
+        assert_eq!(parsed.serialize(), bytes);
+
+        // the answer owner points at the question name, the cname target at its suffix.
+        let answer = HEADER_LENGTH + packet.questions[0].name.wire_len() + 4;
+        assert_eq!(bytes[answer..answer + 2], [0xC0, 0x0C]);
+        assert!(bytes.windows(2).any(|w| w == [0xC0, 0x10]));
+    }
+
+    #[test]
+    fn compressed_rdata_names() {
+        let query = MessageBuilder::query(name("example.com"), record_type::MX).build();
+
+        let mut packet = query.reply();
+        packet.answers = vec![
+            record("example.com", RData::Mx { preference: 10, exchange: name("mail.example.com") }),
+            record("_sip._udp.example.com", RData::Srv {
+                priority: 0,
+                weight: 5,
+                port: 5060,
+                target: name("sip.example.com"),
+            }),
+            record("1.2.0.192.in-addr.arpa", RData::Ptr(name("mail.example.com"))),
+        ];
+        packet.authorities = vec![
+            record("example.com", RData::Soa {
+                mname: name("ns.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        ];
+
+        let bytes = packet.serialize();
+        let parsed = parse(&bytes).unwrap();
+
+        assert_eq!(parsed.answers, packet.answers);
+        assert_eq!(parsed.authorities, packet.authorities);
+        assert_eq!(parsed.serialize(), bytes);
+
+        let contains = |part: &[u8]| bytes.windows(part.len()).any(|w| w == part);
+
+        // mx and soa names point at the question name, srv targets are written out (RFC 2782).
+        assert!(contains(&[0, 10, 4, b'm', b'a', b'i', b'l', 0xC0, 0x0C]));
+        assert!(contains(&[2, b'n', b's', 0xC0, 0x0C]));
+        assert!(contains(&[&[10][..], b"hostmaster", &[0xC0, 0x0C]].concat()));
+        assert!(contains(&[&[0, 0, 0, 5, 0x13, 0xC4][..], &name("sip.example.com").to_wire()]
+            .concat()));
+        assert!(!contains(&name("mail.example.com").to_wire()));
+    }
+
+    #[test]
+    fn rdata_round_trip() {
+        let mut svcb = vec![0, 1];
+        svcb.extend(name("svc.example.com").to_wire());
+        svcb.extend([0, 0, 0, 2, 0, 1]);
+        svcb.extend([0, 1, 0, 6, 2, b'h', b'2', 2, b'h', b'3']);
+        svcb.extend([0, 3, 0, 2, 0x20, 0xFB]);
+        svcb.extend([0, 4, 0, 4, 192, 0, 2, 1]);
+        svcb.extend([0, 6, 0, 16]);
+        svcb.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+
+        let mut https = vec![0, 0];
+        https.extend(name("example.net").to_wire());
+
+        let mut naptr = vec![0, 100, 0, 10, 1, b's', 7];
+        naptr.extend(b"SIP+D2U");
+        naptr.push(0);
+        naptr.extend(name("_sip._udp.example.com").to_wire());
+
+        let mut caa = vec![0x80, 5];
+        caa.extend(b"issue");
+        caa.extend(b"ca.example.net");
+
+        let cases = [
+            (RData::Svcb(ServiceBinding {
+                priority: 1,
+                target: name("svc.example.com"),
+                params: vec![
+                    SvcParam::Mandatory(vec![1]),
+                    SvcParam::Alpn(vec![b"h2".to_vec(), b"h3".to_vec()]),
+                    SvcParam::Port(8443),
+                    SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                    SvcParam::Ipv6Hint(vec!["2001:db8::1".parse().unwrap()]),
+                ],
+            }), svcb),
+            (RData::Https(ServiceBinding {
+                priority: 0,
+                target: name("example.net"),
+                params: Vec::new(),
+            }), https),
+            (RData::Naptr {
+                order: 100,
+                preference: 10,
+                flags: b"s".to_vec(),
+                services: b"SIP+D2U".to_vec(),
+                regexp: Vec::new(),
+                replacement: name("_sip._udp.example.com"),
+            }, naptr),
+            (RData::Caa { flags: 0x80, tag: b"issue".to_vec(), value: b"ca.example.net".to_vec() },
+             caa),
+            (RData::Tlsa { usage: 3, selector: 1, matching_type: 1, data: vec![0xAB; 32] },
+             [&[3, 1, 1][..], &[0xAB; 32]].concat()),
+            (RData::Sshfp { algorithm: 4, fingerprint_type: 2, fingerprint: vec![0xCD; 32] },
+             [&[4, 2][..], &[0xCD; 32]].concat()),
+        ];
+
+        let query = MessageBuilder::query(name("example.com"), record_type::ANY).build();
+
+        for (data, wire) in cases {
+            assert_eq!(RData::from_wire(data.ty(), &wire).unwrap(), data);
+
+            // none of these names may be compressed, so the data is written as is.
+            let mut packet = query.reply();
+            packet.answers = vec![record("example.com", data)];
+
+            let bytes = packet.serialize();
+            assert!(bytes.ends_with(&wire));
+            assert_eq!(parse(&bytes).unwrap().answers, packet.answers);
+        }
+    }
+
+    #[test]
+    fn svc_params_in_order() {
+        // priority 1, root target and a port, then an alpn param out of order or a second port.
+        let port = [0, 1, 0, 0, 3, 0, 2, 0x20, 0xFB];
+        let out_of_order = [&port[..], &[0, 1, 0, 3, 2, b'h', b'2']].concat();
+        let repeated = [&port[..], &[0, 3, 0, 2, 0x01, 0xBB]].concat();
+
+        for wire in [out_of_order, repeated] {
+            assert!(matches!(RData::from_wire(record_type::SVCB, &wire),
+                             Err(ParseError::BadRdata { offset: 9, .. })));
+        }
+    }
+
+    #[test]
+    fn redirected_hints_leave_mandatory() {
+        let binding = ServiceBinding {
+            priority: 1,
+            target: name("."),
+            params: vec![
+                SvcParam::Mandatory(vec![1, 4]),
+                SvcParam::Alpn(vec![b"h2".to_vec()]),
+                SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                SvcParam::Ech(vec![1, 2, 3]),
+            ],
+        };
+
+        let v4 = [Ipv4Addr::new(10, 0, 0, 1)];
+        let v6 = ["fd00::1".parse().unwrap()];
+
+        let mut redirected = binding.clone();
+        redirected.redirect_hints(&v4, &[]);
+        assert_eq!(redirected.params, [
+            SvcParam::Mandatory(vec![1, 4]),
+            SvcParam::Alpn(vec![b"h2".to_vec()]),
+            SvcParam::Ipv4Hint(v4.to_vec()),
+        ]);
+
+        let mut redirected = binding.clone();
+        redirected.redirect_hints(&[], &v6);
+        assert_eq!(redirected.params, [
+            SvcParam::Mandatory(vec![1]),
+            SvcParam::Alpn(vec![b"h2".to_vec()]),
+            SvcParam::Ipv6Hint(v6.to_vec()),
+        ]);
+
+        // nothing left to be mandatory drops the list itself.
+        let mut redirected = binding;
+        redirected.params.remove(1);
+        redirected.params[0] = SvcParam::Mandatory(vec![4]);
+        redirected.redirect_hints(&[], &v6);
+        assert_eq!(redirected.params, [SvcParam::Ipv6Hint(v6.to_vec())]);
     }
 }
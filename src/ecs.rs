@@ -0,0 +1,80 @@
+//! Handling of the EDNS Client Subnet option on the way to and from upstreams.
+
+use crate::dns::*;
+
+use std::net::IpAddr;
+
+/// What to do with the EDNS Client Subnet option of queries before they go upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EcsPolicy {
+    /// Remove any client subnet so nothing about the client leaks upstream.
+    #[default]
+    Strip,
+    /// Forward whatever the client sent.
+    Pass,
+    /// Replace the client subnet with the client's own address, truncated to a prefix.
+    Add {
+        /// Bits kept of IPv4 addresses.
+        v4_prefix: u8,
+        /// Bits kept of IPv6 addresses.
+        v6_prefix: u8,
+    },
+}
+
+impl EcsPolicy {
+    /// Rewrites the client subnet of a query from `client` that is about to be forwarded.
+    pub fn apply(&self, query: &mut DNSPacket, client: IpAddr) {
+        match self {
+            EcsPolicy::Strip => {
+                if let Some(edns) = query.edns.as_mut() {
+                    edns.remove_option(option_code::CLIENT_SUBNET);
+                }
+            },
+            EcsPolicy::Pass => {},
+            EcsPolicy::Add { v4_prefix, v6_prefix } => {
+                // an ipv4 client talking over ipv6 still belongs to an ipv4 network.
+                let client = client.to_canonical();
+
+                let prefix = if client.is_ipv4() { *v4_prefix } else { *v6_prefix };
+                let subnet = ClientSubnet::new(client, prefix);
+
+                query.edns
+                     .get_or_insert_with(|| Edns::new(MAX_UDP_SIZE as u16))
+                     .set_option(subnet.to_option());
+            },
+        }
+    }
+
+    /// Undoes what `apply` added to the response so the client only sees what it asked for.
+    ///
+    /// `client_edns` is the EDNS of the query as the client sent it.
+    pub fn restore(&self, response: &mut DNSPacket, client_edns: Option<&Edns>) {
+        match client_edns {
+            None => response.edns = None,
+            Some(client_edns) => {
+                let client_subnet = client_edns.option(option_code::CLIENT_SUBNET);
+
+                if let Some(edns) = response.edns.as_mut() {
+                    match client_subnet {
+                        None => edns.remove_option(option_code::CLIENT_SUBNET),
+                        // echo the client's own subnet back with the scope upstream returned.
+                        Some(option) if *self != EcsPolicy::Pass => {
+                            let scope = edns.option(option_code::CLIENT_SUBNET)
+                                            .and_then(|o| ClientSubnet::from_option(o).ok())
+                                            .map_or(0, |s| s.scope_prefix);
+
+                            match ClientSubnet::from_option(option) {
+                                Ok(mut subnet) => {
+                                    subnet.scope_prefix = scope.min(subnet.source_prefix);
+                                    edns.set_option(subnet.to_option());
+                                },
+                                Err(_) => edns.remove_option(option_code::CLIENT_SUBNET),
+                            }
+                        },
+                        Some(_) => {},
+                    }
+                }
+            },
+        }
+    }
+}
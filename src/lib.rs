@@ -0,0 +1,70 @@
+//! A DNS proxy that answers queries from local zones, blocklists, rules and its cache, and
+//! forwards the rest to upstream resolvers.
+//!
+//! The codec in `dns` and the `builder` can be used on their own to read and write messages:
+//!
+//! ```
+//! use maldns::builder::MessageBuilder;
+//! use maldns::dns::{record_type, PacketParser};
+//!
+//! let query = MessageBuilder::query("example.com".parse().unwrap(), record_type::AAAA).build();
+//! let bytes = query.serialize();
+//!
+//! assert_eq!(PacketParser::new(&bytes).deserialize().unwrap().questions, query.questions);
+//! ```
+//!
+//! The proxy engine is a `Proxy` made from a `Config`, served over UDP and TCP by `serve`.
+
+pub mod blocklist;
+pub mod builder;
+pub mod cache;
+pub mod config;
+pub mod dns;
+pub mod ecs;
+pub mod master;
+mod pool;
+pub mod proxy;
+pub mod rules;
+pub mod tcp;
+pub mod udp;
+pub mod upstream;
+pub mod zone;
+
+pub use builder::MessageBuilder;
+pub use config::Config;
+pub use dns::{DNSPacket, Header, PacketParser, PacketSerializer, ParseError};
+pub use proxy::Proxy;
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+/// Serves clients on `address` over UDP and TCP, returning only if serving fails.
+///
+/// Ejected upstreams are probed and the cache file, if any, is written in the background.
+pub fn serve(proxy: Arc<Proxy>, address: SocketAddr) -> io::Result<()> {
+    // ejected upstreams are probed in the background until they answer again.
+    for upstreams in proxy.upstream_pools() {
+        let upstreams = Arc::clone(upstreams);
+        thread::spawn(move || upstreams.check_health());
+    }
+
+    // the cache survives restarts, written periodically.
+    if let Some(interval) = proxy.cache_save_interval() {
+        let saver = Arc::clone(&proxy);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            saver.save_cache();
+        });
+    }
+
+    let listener = TcpListener::bind(address)?;
+    let socket = UdpSocket::bind(address)?;
+
+    // tcp clients are served on their own threads, up to a limit.
+    let tcp_proxy = Arc::clone(&proxy);
+    thread::spawn(move || tcp::serve(listener, tcp_proxy));
+
+    udp::serve(socket, proxy)
+}
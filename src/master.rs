@@ -0,0 +1,569 @@
+//! Reading RFC 1035 master files.
+
+use crate::dns::*;
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// Deepest chain of `$INCLUDE`s followed, so files including each other fail instead.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// A word of a master file entry.
+#[derive(Debug)]
+struct Token {
+    /// Text as written, escapes are kept and quotes removed.
+    text: String,
+    /// Quoted tokens are always character strings.
+    quoted: bool,
+}
+
+/// One record or directive, a line or several lines joined by parentheses.
+#[derive(Debug)]
+struct Entry {
+    tokens: Vec<Token>,
+    /// Entries starting with white space reuse the previous owner.
+    indented: bool,
+    /// Line the entry starts on, for error messages.
+    line: usize,
+}
+
+/// Splits a master file into entries, dropping comments (RFC 1035 5.1).
+fn tokenize(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut entry = Entry { tokens: Vec::new(), indented: false, line: 1 };
+    let mut token: Option<Token> = None;
+    let mut chars = text.chars();
+    let (mut line, mut depth, mut quoted, mut comment) = (1, 0, false, false);
+    let mut line_start = true;
+
+    while let Some(c) = chars.next() {
+        if line_start && entry.tokens.is_empty() && token.is_none() && depth == 0 {
+            entry.indented = c == ' ' || c == '\t';
+            entry.line = line;
+        }
+        line_start = c == '\n';
+
+        if comment && c != '\n' {
+            continue;
+        }
+
+        match c {
+            // escapes are decoded later, but mustn't end the token or the quote.
+            '\\' => {
+                let escaped = chars.next().ok_or(format!("line {}: dangling escape.", line))?;
+                let token = token.get_or_insert(Token { text: String::new(), quoted: false });
+                token.text.push(c);
+                token.text.push(escaped);
+            },
+            '"' if quoted => quoted = false,
+            _ if quoted => {
+                line += (c == '\n') as usize;
+                token.as_mut().unwrap().text.push(c);
+            },
+            '"' => {
+                entry.tokens.extend(token.take());
+                token = Some(Token { text: String::new(), quoted: true });
+                quoted = true;
+            },
+            ';' => comment = true,
+            '(' | ')' | ' ' | '\t' | '\r' | '\n' => {
+                entry.tokens.extend(token.take());
+
+                match c {
+                    '(' => depth += 1,
+                    ')' if depth == 0 => return Err(format!("line {}: unbalanced `)`.", line)),
+                    ')' => depth -= 1,
+                    '\n' => {
+                        line += 1;
+                        comment = false;
+
+                        if depth == 0 && !entry.tokens.is_empty() {
+                            let next = Entry { tokens: Vec::new(), indented: false, line };
+                            entries.push(std::mem::replace(&mut entry, next));
+                        }
+                    },
+                    _ => {},
+                }
+            },
+            _ => token.get_or_insert(Token { text: String::new(), quoted: false }).text.push(c),
+        }
+    }
+
+    if quoted || depth > 0 {
+        return Err(format!("line {}: unterminated quote or parenthesis.", entry.line));
+    }
+
+    entry.tokens.extend(token);
+
+    if !entry.tokens.is_empty() {
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Parses a time to live, either in seconds or with `s`, `m`, `h`, `d` and `w` units as in `1h30m`.
+fn parse_ttl(text: &str) -> Result<u32, String> {
+    if let Ok(seconds) = text.parse() {
+        return Ok(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut number: Option<u32> = None;
+
+    for c in text.chars() {
+        let unit = match c.to_ascii_lowercase() {
+            '0'..='9' => {
+                let digit = c.to_digit(10).unwrap();
+                number = number.unwrap_or(0).checked_mul(10).and_then(|n| n.checked_add(digit));
+                if number.is_none() {
+                    return Err(format!("ttl `{}` is too large.", text));
+                }
+                continue;
+            },
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(format!("invalid ttl `{}`.", text)),
+        };
+
+        total = number.take()
+                      .ok_or(format!("invalid ttl `{}`.", text))?
+                      .checked_mul(unit)
+                      .and_then(|n| total.checked_add(n))
+                      .ok_or(format!("ttl `{}` is too large.", text))?;
+    }
+
+    match number {
+        None => Ok(total),
+        Some(_) => Err(format!("invalid ttl `{}`.", text)),
+    }
+}
+
+/// Decodes a character string, handling `\X` and `\DDD` escapes.
+fn parse_character_string(token: &Token) -> Result<Vec<u8>, String> {
+    let mut string: Vec<u8> = Vec::new();
+    let mut bytes = token.text.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            string.push(byte);
+            continue;
+        }
+
+        let escaped = bytes.next().ok_or("dangling escape.")?;
+
+        if escaped.is_ascii_digit() {
+            let digits = [escaped, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+            let value = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|d| d.parse::<u8>().ok())
+                .ok_or(format!("invalid \\DDD escape in `{}`.", token.text))?;
+            string.push(value);
+        } else {
+            string.push(escaped);
+        }
+    }
+
+    if string.len() > 255 {
+        return Err(format!("character string `{}` is longer than 255 bytes.", token.text));
+    }
+
+    Ok(string)
+}
+
+/// Decodes hex split over any number of tokens.
+fn parse_hex(tokens: &[Token]) -> Result<Vec<u8>, String> {
+    let digits: String = tokens.iter().map(|t| t.text.as_str()).collect();
+
+    if !digits.len().is_multiple_of(2) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex `{}`.", digits));
+    }
+
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+/// Reads the records of a master file, following `$INCLUDE` directives.
+struct Parser {
+    origin: DomainName,
+    /// Set by `$TTL`, otherwise the last explicit ttl is reused.
+    default_ttl: Option<u32>,
+    last_owner: Option<DomainName>,
+    records: Vec<Record>,
+}
+
+impl Parser {
+    /// Resolves `@` and names not ending in a dot against the origin.
+    fn name(&self, token: &Token) -> Result<DomainName, String> {
+        let text = token.text.as_str();
+
+        if text == "@" {
+            return Ok(self.origin.clone());
+        }
+
+        // a dot escaped by an odd number of backslashes is part of the last label.
+        let absolute = text.strip_suffix('.')
+                           .is_some_and(|t| (t.len() - t.trim_end_matches('\\').len()) % 2 == 0);
+
+        let name: DomainName = text.parse()?;
+
+        match absolute {
+            true => Ok(name),
+            false => name.concat(&self.origin),
+        }
+    }
+
+    /// Parses the entries of a file, `path` is used to find included files.
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read zone file {}: {}", path.display(), e))?;
+
+        for entry in tokenize(&text).map_err(|e| format!("{}: {}", path.display(), e))? {
+            self.parse_entry(&entry, path, depth)
+                .map_err(|e| format!("{}:{}: {}", path.display(), entry.line, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_entry(&mut self, entry: &Entry, path: &Path, depth: usize) -> Result<(), String> {
+        let tokens = entry.tokens.as_slice();
+
+        match tokens[0].text.as_str() {
+            "$ORIGIN" if !entry.indented => {
+                let [_, origin] = tokens else { return Err("expected `$ORIGIN <name>`.".to_string()) };
+                self.origin = self.name(origin)?;
+            },
+            "$TTL" if !entry.indented => {
+                let [_, ttl] = tokens else { return Err("expected `$TTL <ttl>`.".to_string()) };
+                self.default_ttl = Some(parse_ttl(&ttl.text)?);
+            },
+            "$INCLUDE" if !entry.indented => {
+                let (file, origin) = match tokens {
+                    [_, file] => (file, self.origin.clone()),
+                    [_, file, origin] => (file, self.name(origin)?),
+                    _ => return Err("expected `$INCLUDE <file> [origin]`.".to_string()),
+                };
+
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(format!("includes nest deeper than {}.", MAX_INCLUDE_DEPTH));
+                }
+
+                // relative paths start at the including file, which keeps its origin afterwards.
+                let file = path.parent().unwrap_or(Path::new("")).join(&file.text);
+                let origin = std::mem::replace(&mut self.origin, origin);
+                let result = self.parse_file(&file, depth + 1);
+                self.origin = origin;
+                result?;
+            },
+            directive if directive.starts_with('$') && !entry.indented => {
+                return Err(format!("unknown directive `{}`.", directive));
+            },
+            _ => self.parse_record(entry)?,
+        }
+
+        Ok(())
+    }
+
+    /// Parses `[owner] [ttl] [class] <type> <rdata>...`, ttl and class in either order.
+    fn parse_record(&mut self, entry: &Entry) -> Result<(), String> {
+        let mut tokens = entry.tokens.as_slice();
+
+        let owner = match entry.indented {
+            true => self.last_owner.clone().ok_or("record without an owner.")?,
+            false => {
+                let owner = self.name(&tokens[0])?;
+                tokens = &tokens[1..];
+                owner
+            },
+        };
+
+        let (mut ttl, mut class) = (None, None);
+
+        let ty = loop {
+            let [token, rest @ ..] = tokens else { return Err("record without a type.".to_string()) };
+            tokens = rest;
+
+            match token.text.as_str() {
+                text if ttl.is_none() && text.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl = Some(parse_ttl(text)?);
+                },
+                text if class.is_none() && text.eq_ignore_ascii_case("IN") => {
+                    class = Some(record_class::IN);
+                },
+                text => break record_type::from_name(text)
+                    .filter(|&ty| ty != record_type::ANY && ty != record_type::OPT)
+                    .ok_or(format!("unknown record type or class `{}`.", text))?,
+            }
+        };
+
+        // without `$TTL` the last explicit ttl carries over, as in RFC 1035.
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None => self.default_ttl
+                        .or_else(|| self.records.last().map(|r| r.ttl))
+                        .ok_or("record without a ttl and no `$TTL` before it.")?,
+        };
+
+        let data = self.parse_rdata(ty, tokens)?;
+
+        self.last_owner = Some(owner.clone());
+        self.records.push(Record { name: owner, class: record_class::IN, ttl, data });
+
+        Ok(())
+    }
+
+    /// Parses the presentation format of record data, types without a typed form need the
+    /// RFC 3597 `\# <length> <hex>` form.
+    fn parse_rdata(&self, ty: u16, tokens: &[Token]) -> Result<RData, String> {
+        let number = |token: &Token| token.text.parse::<u32>()
+            .map_err(|_| format!("invalid number `{}`.", token.text));
+        let u8 = |token: &Token| token.text.parse::<u8>()
+            .map_err(|_| format!("invalid number `{}`.", token.text));
+        let u16 = |token: &Token| token.text.parse::<u16>()
+            .map_err(|_| format!("invalid number `{}`.", token.text));
+
+        if let [generic, length, hex @ ..] = tokens {
+            if generic.text == "\\#" && !generic.quoted {
+                let data = parse_hex(hex)?;
+
+                if number(length)? as usize != data.len() {
+                    return Err(format!("generic data is not {} bytes long.", length.text));
+                }
+
+                return RData::from_wire(ty, &data).map_err(|e| e.to_string());
+            }
+        }
+
+        let data = match (ty, tokens) {
+            (record_type::A, [address]) => RData::A(address.text.parse::<Ipv4Addr>()
+                .map_err(|_| format!("invalid address `{}`.", address.text))?),
+            (record_type::AAAA, [address]) => RData::Aaaa(address.text.parse::<Ipv6Addr>()
+                .map_err(|_| format!("invalid address `{}`.", address.text))?),
+            (record_type::CNAME, [name]) => RData::Cname(self.name(name)?),
+            (record_type::NS, [name]) => RData::Ns(self.name(name)?),
+            (record_type::PTR, [name]) => RData::Ptr(self.name(name)?),
+            (record_type::MX, [preference, exchange]) => RData::Mx {
+                preference: u16(preference)?,
+                exchange: self.name(exchange)?,
+            },
+            (record_type::TXT, strings) if !strings.is_empty() => RData::Txt(strings
+                .iter()
+                .map(parse_character_string)
+                .collect::<Result<_, _>>()?),
+            (record_type::SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => RData::Soa {
+                mname: self.name(mname)?,
+                rname: self.name(rname)?,
+                serial: number(serial)?,
+                refresh: parse_ttl(&refresh.text)?,
+                retry: parse_ttl(&retry.text)?,
+                expire: parse_ttl(&expire.text)?,
+                minimum: parse_ttl(&minimum.text)?,
+            },
+            (record_type::SRV, [priority, weight, port, target]) => RData::Srv {
+                priority: u16(priority)?,
+                weight: u16(weight)?,
+                port: u16(port)?,
+                target: self.name(target)?,
+            },
+            (record_type::NAPTR, [order, preference, flags, services, regexp, replacement]) => RData::Naptr {
+                order: u16(order)?,
+                preference: u16(preference)?,
+                flags: parse_character_string(flags)?,
+                services: parse_character_string(services)?,
+                regexp: parse_character_string(regexp)?,
+                replacement: self.name(replacement)?,
+            },
+            (record_type::SSHFP, [algorithm, fingerprint_type, fingerprint @ ..]) => RData::Sshfp {
+                algorithm: u8(algorithm)?,
+                fingerprint_type: u8(fingerprint_type)?,
+                fingerprint: parse_hex(fingerprint)?,
+            },
+            (record_type::TLSA, [usage, selector, matching_type, data @ ..]) => RData::Tlsa {
+                usage: u8(usage)?,
+                selector: u8(selector)?,
+                matching_type: u8(matching_type)?,
+                data: parse_hex(data)?,
+            },
+            (record_type::CAA, [flags, tag, value]) => RData::Caa {
+                flags: u8(flags)?,
+                tag: tag.text.as_bytes().to_vec(),
+                value: parse_character_string(value)?,
+            },
+            (record_type::A | record_type::AAAA | record_type::CNAME | record_type::NS
+                | record_type::PTR | record_type::MX | record_type::TXT | record_type::SOA
+                | record_type::SRV | record_type::NAPTR | record_type::SSHFP | record_type::TLSA
+                | record_type::CAA, _) => {
+                return Err(format!("wrong number of fields for a type {} record.", ty));
+            },
+            _ => return Err(format!("type {} records need the generic `\\#` form.", ty)),
+        };
+
+        Ok(data)
+    }
+}
+
+/// Reads the records of the master file at `path` (RFC 1035 5), relative names are completed
+/// with `origin` until a `$ORIGIN` says otherwise.
+pub fn load(path: &str, origin: &DomainName) -> Result<Vec<Record>, String> {
+    let mut parser = Parser {
+        origin: origin.clone(),
+        default_ttl: None,
+        last_owner: None,
+        records: Vec::new(),
+    };
+
+    parser.parse_file(Path::new(path), 0)?;
+    Ok(parser.records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    /// Writes `files` into a fresh directory and loads the first one with origin example.com.
+    fn load_files(files: &[(&str, &str)]) -> Result<Vec<Record>, String> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let directory = std::env::temp_dir().join(format!("maldns-master-{}-{}",
+            std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        for (file, text) in files {
+            std::fs::write(directory.join(file), text).unwrap();
+        }
+
+        let path = directory.join(files[0].0);
+        let records = load(path.to_str().unwrap(), &name("example.com"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+        records
+    }
+
+    #[test]
+    fn parentheses_comments_and_strings() {
+        let records = load_files(&[("example.zone", concat!(
+            "$TTL 1h30m\n",
+            "@ IN SOA ns hostmaster ( 2024010101 ; serial\n",
+            "        3600 900 ; refresh and retry\n",
+            "        1w 300 )\n",
+            "txt TXT \"a \\\"quoted\\\" string; not a comment\" \\065\\066C\n",
+        ))]).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].ttl, 5400);
+        assert_eq!(records[0].data, RData::Soa {
+            mname: name("ns.example.com"),
+            rname: name("hostmaster.example.com"),
+            serial: 2024010101,
+            refresh: 3600,
+            retry: 900,
+            expire: 604800,
+            minimum: 300,
+        });
+
+        assert_eq!(records[1].name, name("txt.example.com"));
+        assert_eq!(records[1].data, RData::Txt(vec![
+            b"a \"quoted\" string; not a comment".to_vec(),
+            b"ABC".to_vec(),
+        ]));
+    }
+
+    #[test]
+    fn directives() {
+        let records = load_files(&[
+            ("example.zone", concat!(
+                "$ORIGIN example.net.\n",
+                "$TTL 300\n",
+                "www A 192.0.2.1\n",
+                "$INCLUDE sub.zone sub\n",
+                "after A 192.0.2.3\n",
+            )),
+            ("sub.zone", "host A 192.0.2.2\n$ORIGIN elsewhere.\n"),
+        ]).unwrap();
+
+        let names: Vec<&DomainName> = records.iter().map(|r| &r.name).collect();
+
+        assert_eq!(names, [&name("www.example.net"), &name("host.sub.example.net"),
+                           &name("after.example.net")]);
+        assert!(records.iter().all(|r| r.ttl == 300));
+
+        let error = load_files(&[("loop.zone", "$INCLUDE loop.zone\n")]).unwrap_err();
+        assert!(error.contains("includes nest deeper than 8"), "{}", error);
+
+        assert!(load_files(&[("bad.zone", "$GENERATE 1-10 a A 192.0.2.1\n")]).is_err());
+        assert!(load_files(&[("bad.zone", "$TTL\n")]).is_err());
+    }
+
+    #[test]
+    fn owners() {
+        let records = load_files(&[("example.zone", concat!(
+            "$TTL 60\n",
+            "@ A 192.0.2.1\n",
+            "  AAAA 2001:db8::1\n",
+            "mail 120 IN MX 10 mx\n",
+            "abs.example.net. A 192.0.2.2\n",
+            "\tTXT \"inherited\"\n",
+            "escaped\\. A 192.0.2.3\n",
+        ))]).unwrap();
+
+        let owners: Vec<(DomainName, u32, u16)> = records
+            .iter()
+            .map(|r| (r.name.clone(), r.ttl, r.ty()))
+            .collect();
+
+        assert_eq!(owners, [
+            (name("example.com"), 60, record_type::A),
+            (name("example.com"), 60, record_type::AAAA),
+            (name("mail.example.com"), 120, record_type::MX),
+            (name("abs.example.net"), 60, record_type::A),
+            (name("abs.example.net"), 60, record_type::TXT),
+            (name("escaped\\..example.com"), 60, record_type::A),
+        ]);
+
+        assert_eq!(records[2].data, RData::Mx { preference: 10, exchange: name("mx.example.com") });
+
+        assert!(load_files(&[("example.zone", " A 192.0.2.1\n")]).is_err());
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(parse_ttl("300"), Ok(300));
+        assert_eq!(parse_ttl("1h30m"), Ok(5400));
+        assert_eq!(parse_ttl("2H"), Ok(7200));
+        assert_eq!(parse_ttl("1w1d1s"), Ok(691201));
+
+        for ttl in ["1x", "h", "5h3", "9999999999", "9999999w"] {
+            assert!(parse_ttl(ttl).is_err(), "{}", ttl);
+        }
+
+        // without `$TTL` the last explicit ttl carries over.
+        let records = load_files(&[("example.zone", "a 1h A 192.0.2.1\nb A 192.0.2.2\n")]).unwrap();
+        assert_eq!(records[1].ttl, 3600);
+    }
+
+    #[test]
+    fn error_lines() {
+        let error = load_files(&[("example.zone", concat!(
+            "$TTL 60\n",
+            "a A 192.0.2.1\n",
+            "b ( A ; an address\n",
+            "    192.0.2.300 )\n",
+        ))]).unwrap_err();
+
+        assert!(error.ends_with("example.zone:3: invalid address `192.0.2.300`."), "{}", error);
+
+        let error = load_files(&[("example.zone", "$TTL 60\n\na A 192.0.2.1 )\n")]).unwrap_err();
+        assert!(error.ends_with("line 3: unbalanced `)`."), "{}", error);
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of threads that run jobs handed to them in order of arrival.
+pub struct WorkerPool {
+    sender: Sender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..size {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || work(receiver));
+        }
+
+        Self { sender }
+    }
+
+    /// Queues `job` to run on the next free worker.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        // workers only stop once the pool is dropped, so this can't fail.
+        self.sender.send(Box::new(job)).unwrap();
+    }
+}
+
+/// Runs jobs until the pool's sender is dropped.
+fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // the lock is released before the job runs so other workers can pick up jobs.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(j) => j,
+            Err(_) => break,
+        };
+
+        job();
+    }
+}
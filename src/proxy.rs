@@ -0,0 +1,338 @@
+//! The proxy engine, answering queries locally or through upstreams independently of transport.
+
+use crate::blocklist::{Blocklist, Verdict};
+use crate::cache::{self, Cache};
+use crate::config::Config;
+use crate::dns::*;
+use crate::rules::{RuleSet, DEFAULT_TTL};
+use crate::tcp;
+use crate::master;
+use crate::pool::WorkerPool;
+use crate::zone::{LocalZones, Zone};
+use crate::upstream::UpstreamPool;
+
+use std::net::IpAddr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a client waits on the upstreams before it's answered from stale, if it can be,
+/// while the upstreams are still asked (RFC 8767 5).
+pub const CLIENT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(1800);
+
+/// Amount of threads resolving queries over tcp for clients that may stop waiting on them.
+const RESOLVER_COUNT: usize = 32;
+
+/// What a transport does with the bytes of a client query, see `Proxy::precheck`.
+pub enum Precheck {
+    /// The query, to be answered as usual.
+    Query(DNSPacket),
+    /// Sent back to the client in place of an answer.
+    Respond(DNSPacket),
+    /// Nothing the client would recognize can be sent back.
+    Ignore,
+}
+
+/// Policy shared by every transport, turns client queries into upstream queries and
+/// upstream responses into client responses.
+pub struct Proxy {
+    config: Config,
+    /// Where queries are forwarded to by default.
+    upstreams: Arc<UpstreamPool>,
+    /// Upstreams for queries at or below a suffix, see `upstreams_for`.
+    forwards: Vec<(DomainName, Arc<UpstreamPool>)>,
+    /// Decide how responses are rewritten.
+    rules: RuleSet,
+    /// Queried names that are answered without asking upstream.
+    blocklist: Blocklist,
+    /// Names answered from our own records.
+    zones: LocalZones,
+    /// Upstream responses, answered from until they expire.
+    cache: Cache,
+    /// Runs the resolutions of `resolve_tcp_until`.
+    resolvers: WorkerPool,
+}
+
+impl Proxy {
+    /// Fails if a blocklist, allowlist, zone or hosts file can't be read.
+    pub fn new(config: Config) -> Result<Self, String> {
+        let upstreams = Arc::new(UpstreamPool::new(&config.upstreams, config.strategy));
+
+        let forwards = config.forwards
+                             .iter()
+                             .map(|f| {
+                                 let pool = UpstreamPool::new(&f.upstreams, config.strategy);
+                                 (f.suffix.clone(), Arc::new(pool))
+                             })
+                             .collect();
+
+        let ttl = config.local_ttl.unwrap_or(DEFAULT_TTL);
+        let rules = RuleSet::new(config.rules.clone(), config.rule_order, ttl);
+
+        let mut blocklist = Blocklist::default();
+
+        for path in &config.blocklists {
+            let count = blocklist.load(path, false)?;
+            eprintln!("Info: loaded {} blocked domains from {}", count, path);
+        }
+
+        for path in &config.allowlists {
+            let count = blocklist.load(path, true)?;
+            eprintln!("Info: loaded {} allowed domains from {}", count, path);
+        }
+
+        // zones go first so host entries end up in the zone they belong to.
+        let mut zones = LocalZones::new(ttl);
+
+        for (origin, path) in &config.zone_files {
+            let records = master::load(path, origin)?;
+            eprintln!("Info: loaded {} records of zone {} from {}", records.len(), origin, path);
+            zones.add(Zone::from_records(origin.clone(), records)?);
+        }
+
+        config.local_zones.iter().for_each(|origin| zones.add_zone(origin.clone()));
+
+        for (address, names) in &config.local_hosts {
+            names.iter().for_each(|name| zones.add_host(*address, name));
+        }
+
+        for path in &config.hosts_files {
+            let count = zones.load_hosts(path)?;
+            eprintln!("Info: loaded {} local names from {}", count, path);
+        }
+
+        let cache = Cache::new(config.cache);
+
+        // the cache is only an optimization, a snapshot we can't use just means starting cold.
+        if let Some((path, _)) = &config.cache_file {
+            match cache.load(path) {
+                Ok(count) => eprintln!("Info: loaded {} cache entries from {}", count, path),
+                Err(e) => eprintln!("Error: starting with an empty cache: {}", e),
+            }
+        }
+
+        let resolvers = WorkerPool::new(RESOLVER_COUNT);
+
+        Ok(Self { config, upstreams, forwards, rules, blocklist, zones, cache, resolvers })
+    }
+
+    /// Parses the query bytes from `client`, unless they're answered right away.
+    ///
+    /// Malformed queries get a FORMERR if their header can be answered and nothing otherwise,
+    /// EDNS versions other than 0 get a BADVERS (RFC 6891 6.1.3).
+    pub fn precheck(&self, bytes: &[u8], client: IpAddr) -> Precheck {
+        let query = match PacketParser::new(bytes).deserialize() {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("Error: malformed query from {}: {}", client, e.with_context(bytes));
+                return DNSPacket::format_error(bytes).map_or(Precheck::Ignore, Precheck::Respond);
+            },
+        };
+
+        // only EDNS version 0 exists, newer ones are refused before anything else.
+        if query.edns.as_ref().is_some_and(|e| e.version != 0) {
+            return Precheck::Respond(query.bad_version());
+        }
+
+        Precheck::Query(query)
+    }
+
+    /// Answers the query from `client` without the upstream if it's for a local name, a
+    /// blocked name, a rule can answer it or its answer is cached.
+    ///
+    /// Every blocklist decision is logged along with the entries that caused it.
+    pub fn local_response(self: &Arc<Self>, query: &DNSPacket, client: IpAddr)
+        -> Option<DNSPacket> {
+        if let Some(response) = self.zones.answer(query) {
+            return Some(response);
+        }
+
+        let mut blocked = false;
+
+        for question in &query.questions {
+            match self.blocklist.check(&question.name) {
+                Some(Verdict::Blocked(block)) => {
+                    eprintln!("Info: blocked {} by {}", question.name, block);
+                    blocked = true;
+                },
+                Some(Verdict::Allowed { block, allow }) => {
+                    eprintln!("Info: allowed {} by {} over {}", question.name, allow, block);
+                },
+                None => {},
+            }
+        }
+
+        if blocked {
+            let ttl = self.config.local_ttl.unwrap_or(DEFAULT_TTL);
+            return Some(self.config.block_response.respond(query, ttl));
+        }
+
+        if let Some(response) = self.rules.respond(query, client) {
+            return Some(response);
+        }
+
+        // the target of an alias may be ours too.
+        let target_query = self.target_query(query, client);
+
+        if self.rules.alias(query, client).is_some() {
+            if let Some(mut response) = self.zones.answer(&target_query) {
+                self.rules.apply(query, &mut response, client);
+                return Some(response);
+            }
+        }
+
+        // the subnet decides which cached answers apply, as it would decide upstream.
+        let subnet = cache::client_subnet(&self.upstream_query(query, client));
+        let (mut response, prefetch) = self.cache.get(&target_query, subnet.as_ref())?;
+
+        // refreshed over tcp in the background, which needs no in-flight bookkeeping.
+        if prefetch {
+            let (proxy, query) = (Arc::clone(self), query.clone());
+
+            thread::spawn(move || {
+                if let Err(e) = proxy.resolve_tcp(&query, client) {
+                    eprintln!("Error: prefetch failed: {}", e);
+                }
+            });
+        }
+
+        self.config.ecs.restore(&mut response, query.edns.as_ref());
+        self.rules.apply(query, &mut response, client);
+        Some(response)
+    }
+
+    /// Answers the query from `client` from an expired cache entry, for when the upstreams
+    /// fail to (RFC 8767).
+    pub fn stale_response(&self, query: &DNSPacket, client: IpAddr) -> Option<DNSPacket> {
+        let subnet = cache::client_subnet(&self.upstream_query(query, client));
+        let target_query = self.target_query(query, client);
+        let mut response = self.cache.get_stale(&target_query, subnet.as_ref())?;
+
+        self.config.ecs.restore(&mut response, query.edns.as_ref());
+        self.rules.apply(query, &mut response, client);
+        Some(response)
+    }
+
+    /// Writes the cache to its file, if it has one.
+    pub fn save_cache(&self) {
+        let Some((path, _)) = &self.config.cache_file else { return };
+
+        match self.cache.save(path) {
+            Ok(count) => eprintln!("Info: saved {} cache entries to {}", count, path),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+
+    /// How often the cache is written to its file, `None` if it has none.
+    pub fn cache_save_interval(&self) -> Option<Duration> {
+        self.config.cache_file.as_ref().map(|(_, interval)| *interval)
+    }
+
+    /// Upstreams to forward `query` to, the forward rule with the longest matching suffix wins.
+    pub fn upstreams_for(&self, query: &DNSPacket) -> &Arc<UpstreamPool> {
+        let Some(question) = query.questions.first() else { return &self.upstreams };
+
+        self.forwards
+            .iter()
+            .filter(|(suffix, _)| question.name.is_subdomain_of(suffix))
+            .max_by_key(|(suffix, _)| suffix.label_count())
+            .map_or(&self.upstreams, |(_, pool)| pool)
+    }
+
+    /// Every upstream pool, the default one first.
+    pub fn upstream_pools(&self) -> impl Iterator<Item = &Arc<UpstreamPool>> {
+        std::iter::once(&self.upstreams).chain(self.forwards.iter().map(|(_, pool)| pool))
+    }
+
+    /// The query from `client` as the cache and the upstreams see it, asking for the target
+    /// instead of a name a rule aliases.
+    fn target_query(&self, query: &DNSPacket, client: IpAddr) -> DNSPacket {
+        let mut target_query = query.clone();
+
+        if let Some(target) = self.rules.alias(query, client) {
+            target_query.questions[0].name = target.clone();
+        }
+
+        target_query
+    }
+
+    /// Creates the query to send upstream for a query from `client`.
+    pub fn upstream_query(&self, query: &DNSPacket, client: IpAddr) -> DNSPacket {
+        let mut upstream_query = self.target_query(query, client);
+        self.config.ecs.apply(&mut upstream_query, client);
+        upstream_query
+    }
+
+    /// Applies the remaining rewrite rules to an upstream response to the `query` from `client`.
+    pub fn client_response(&self, query: &DNSPacket, response: &mut DNSPacket, client: IpAddr) {
+        // the upstream saw its own transaction id.
+        response.header.id = query.header.id;
+
+        // cached under the question the upstream answered, which an alias may have changed.
+        self.cache.insert(&self.target_query(query, client), response);
+
+        self.config.ecs.restore(response, query.edns.as_ref());
+        self.rules.apply(query, response, client);
+    }
+
+    /// Resolves a query from `client` by forwarding it to the upstreams over TCP.
+    ///
+    /// Also used to retry queries whose UDP response came back truncated.
+    pub fn resolve_tcp(&self, query: &DNSPacket, client: IpAddr) -> Result<DNSPacket, String> {
+        let upstream_query = self.upstream_query(query, client);
+        let bytes = upstream_query.serialize();
+        let upstreams = self.upstreams_for(&upstream_query);
+        let mut error = String::from("no upstream to ask.");
+
+        for index in upstreams.candidates() {
+            let upstream = upstreams.get(index);
+            let sent = Instant::now();
+
+            let response = tcp::exchange(upstream.address, &bytes, upstream.timeout)
+                .map_err(|e| format!("tcp exchange with {} failed: {}", upstream.address, e))
+                .and_then(|b| PacketParser::new(&b).deserialize().map_err(|e| e.with_context(&b)));
+
+            match response {
+                Ok(mut response) if response.header.id == upstream_query.header.id => {
+                    upstreams.report_success(index, sent.elapsed());
+                    self.client_response(query, &mut response, client);
+                    return Ok(response);
+                },
+                Ok(_) => error = format!("response id from {} doesn't match.", upstream.address),
+                Err(e) => error = e,
+            }
+
+            upstreams.report_failure(index);
+        }
+
+        Err(error)
+    }
+
+    /// Resolves a query from `client` like `resolve_tcp`, but answers from stale once
+    /// `deadline` passed if it can, letting the resolution refresh the cache in the background.
+    pub fn resolve_tcp_until(self: &Arc<Self>, query: &DNSPacket, client: IpAddr, 
+        deadline: Instant) -> Result<DNSPacket, String> {
+        let (sender, receiver) = mpsc::channel();
+        let (proxy, resolved_query) = (Arc::clone(self), query.clone());
+
+        self.resolvers.execute(move || {
+            // the client may have been answered from stale already, nobody's listening then.
+            let _ = sender.send(proxy.resolve_tcp(&resolved_query, client));
+        });
+
+        let stopped = || "resolution stopped before it finished.".to_string();
+
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(response) => return response,
+            Err(RecvTimeoutError::Disconnected) => return Err(stopped()),
+            Err(RecvTimeoutError::Timeout) => {},
+        }
+
+        if let Some(response) = self.stale_response(query, client) {
+            return Ok(response);
+        }
+
+        receiver.recv().map_err(|_| stopped())?
+    }
+}
@@ -0,0 +1,543 @@
+//! Rules answering, rewriting or passing queries by name, type and client.
+
+use crate::dns::*;
+
+use regex::Regex;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// An address range in CIDR notation, like `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    /// First address of the range, the bits past `prefix` are cleared.
+    pub address: IpAddr,
+    /// Amount of leading bits that are fixed.
+    pub prefix: u8,
+}
+
+impl Network {
+    /// Whether `address` lies within the range.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // ipv4 clients talking over ipv6 should still match ipv4 networks.
+        let address = address.to_canonical();
+
+        address.is_ipv4() == self.address.is_ipv4() 
+            && mask_address(address, self.prefix) == self.address
+    }
+}
+
+/// Parses `address/prefix`, a lone address is a network of just itself.
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = s.split_once('/').unwrap_or((s, ""));
+
+        let address: IpAddr = address.parse()
+            .map_err(|_| format!("invalid network address `{}`", s))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            "" => max_prefix,
+            p => p.parse::<u8>()
+                  .ok()
+                  .filter(|&p| p <= max_prefix)
+                  .ok_or(format!("invalid network prefix `{}`", s))?,
+        };
+
+        Ok(Self { address: mask_address(address, prefix), prefix })
+    }
+}
+
+/// How a rule matches the question name.
+#[derive(Debug, Clone)]
+pub enum NameMatch {
+    /// Every name.
+    Any,
+    /// Only the name itself.
+    Exact(DomainName),
+    /// The name and everything below it.
+    Suffix(DomainName),
+    /// Everything below the name, but not the name itself, written `*.name`.
+    Wildcard(DomainName),
+    /// The presentation format of the name, lower cased and without the trailing dot.
+    Regex(Regex),
+}
+
+impl NameMatch {
+    /// Whether `name` is matched.
+    pub fn matches(&self, name: &DomainName) -> bool {
+        match self {
+            NameMatch::Any => true,
+            NameMatch::Exact(exact) => name == exact,
+            NameMatch::Suffix(suffix) => name.is_subdomain_of(suffix),
+            NameMatch::Wildcard(parent) => name.is_strict_subdomain_of(parent),
+            NameMatch::Regex(regex) => regex.is_match(&name.to_string().to_ascii_lowercase()),
+        }
+    }
+}
+
+/// Parses `any`, `exact:<name>`, `suffix:<name>`, `*.<name>` or `regex:<pattern>`.
+impl std::str::FromStr for NameMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "any" {
+            return Ok(NameMatch::Any);
+        }
+
+        if let Some(parent) = s.strip_prefix("*.") {
+            return Ok(NameMatch::Wildcard(parent.parse()?));
+        }
+
+        match s.split_once(':') {
+            Some(("exact", name)) => Ok(NameMatch::Exact(name.parse()?)),
+            Some(("suffix", name)) => Ok(NameMatch::Suffix(name.parse()?)),
+            Some(("regex", pattern)) => Regex::new(pattern)
+                .map(NameMatch::Regex)
+                .map_err(|e| format!("invalid regex `{}`: {}", pattern, e)),
+            _ => Err(format!("invalid name match `{}`", s)),
+        }
+    }
+}
+
+/// What to do with a query once a rule matches it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Forward the query and leave the upstream's response alone.
+    Pass,
+    /// Answer with the given addresses, types without addresses given get no records.
+    Redirect {
+        /// Addresses of A answers.
+        v4: Vec<Ipv4Addr>,
+        /// Addresses of AAAA answers.
+        v6: Vec<Ipv6Addr>,
+    },
+    /// A name error, as if the name didn't exist.
+    NxDomain,
+    /// An empty answer with no error.
+    NoData,
+    /// Refuse to answer.
+    Refused,
+    /// Answer with an alias to the given name, followed by the records of the name itself as
+    /// the cache or the upstreams have them.
+    Cname(DomainName),
+}
+
+/// Matches queries by name, type and client, and decides what to do with them.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// Question names to match.
+    pub name: NameMatch,
+    /// Question type to match, any type if `None`.
+    pub qtype: Option<u16>,
+    /// Clients to match, any client if `None`.
+    pub client: Option<Network>,
+    /// Higher goes first when the rule set is ordered by priority.
+    pub priority: i32,
+    /// What's done with matched queries.
+    pub action: Action,
+    /// Time to live of the records made up by the action, the rule set's default if `None`.
+    pub ttl: Option<u32>,
+}
+
+impl Rule {
+    /// Whether the rule applies to `question` asked by `client`.
+    pub fn matches(&self, question: &Question, client: IpAddr) -> bool {
+        self.qtype.is_none_or(|ty| ty == question.ty)
+            && self.client.is_none_or(|network| network.contains(client))
+            && self.name.matches(&question.name)
+    }
+}
+
+/// Parses `<name match> <action> [type <qtype>] [client <network>] [priority <n>] [ttl <seconds>]`.
+///
+/// The actions are `pass`, `nxdomain`, `nodata`, `refused`, `cname <target>` and
+/// `redirect <address>[,<address>...]`.
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let mut next = |what: &str| words.next().ok_or(format!("missing {} in rule `{}`", what, s));
+
+        let name: NameMatch = next("name match")?.parse()?;
+
+        let action = match next("action")? {
+            "pass" => Action::Pass,
+            "nxdomain" => Action::NxDomain,
+            "nodata" => Action::NoData,
+            "refused" => Action::Refused,
+            "cname" => Action::Cname(next("cname target")?.parse()?),
+            "redirect" => {
+                let (v4, v6) = parse_addresses(next("redirect address")?, "redirect")?;
+                Action::Redirect { v4, v6 }
+            },
+            action => return Err(format!("unknown action `{}`", action)),
+        };
+
+        let mut rule = Rule { name, qtype: None, client: None, priority: 0, action, ttl: None };
+
+        while let Some(option) = words.next() {
+            let value = words.next().ok_or(format!("missing value for `{}`", option))?;
+
+            match option {
+                "type" => rule.qtype = Some(record_type::from_name(value)
+                    .ok_or(format!("unknown record type `{}`", value))?),
+                "client" => rule.client = Some(value.parse()?),
+                "priority" => rule.priority = value.parse()
+                    .map_err(|_| format!("invalid priority `{}`", value))?,
+                "ttl" => rule.ttl = Some(value.parse()
+                    .map_err(|_| format!("invalid ttl `{}`", value))?),
+                _ => return Err(format!("unknown rule option `{}`", option)),
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+/// In which order rules are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RuleOrder {
+    /// The first rule in the configuration that matches wins.
+    #[default]
+    FirstMatch,
+    /// The matching rule with the highest priority wins, ties go to the earlier rule.
+    Priority,
+}
+
+/// Rules in the order they're tried.
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    ttl: u32,
+}
+
+impl RuleSet {
+    /// Made up records live for `ttl` seconds unless their rule says otherwise.
+    pub fn new(mut rules: Vec<Rule>, order: RuleOrder, ttl: u32) -> Self {
+        if order == RuleOrder::Priority {
+            // stable, so configuration order breaks ties.
+            rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+        }
+
+        Self { rules, ttl }
+    }
+
+    /// The rule deciding what happens to `question` from `client`, if any.
+    pub fn find(&self, question: &Question, client: IpAddr) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.matches(question, client))
+    }
+
+    /// Answers a query from `client` with the action of the matching rule, without asking
+    /// any upstream. `None` if the query has to be forwarded.
+    pub fn respond(&self, query: &DNSPacket, client: IpAddr) -> Option<DNSPacket> {
+        let question = query.questions.first()?;
+        let rule = self.find(question, client)?;
+        let ttl = rule.ttl.unwrap_or(self.ttl);
+
+        let response = match &rule.action {
+            // aliases are followed through the cache and the upstreams, see `alias`.
+            Action::Pass | Action::Cname(_) => return None,
+            Action::Redirect { v4, v6 } => match question.ty {
+                record_type::A => query.synthesize(v4.iter().map(|&a| RData::A(a)), ttl),
+                record_type::AAAA => query.synthesize(v6.iter().map(|&a| RData::Aaaa(a)), ttl),
+                // other types can carry addresses too, those are replaced by `apply`.
+                _ => return None,
+            },
+            Action::NxDomain => empty(query, rcode::NXDOMAIN),
+            Action::NoData => empty(query, rcode::NOERROR),
+            Action::Refused => empty(query, rcode::REFUSED),
+        };
+
+        Some(response)
+    }
+
+    /// The name a rule aliases the first question of `query` from `client` to, which is
+    /// asked for instead of it.
+    pub fn alias(&self, query: &DNSPacket, client: IpAddr) -> Option<&DomainName> {
+        match &self.find(query.questions.first()?, client)?.action {
+            Action::Cname(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Applies the action of the matching rule to a response to `query` `respond` left alone.
+    ///
+    /// Responses for the target of an alias get the question of `query` back, and the alias
+    /// in front of the target's records.
+    pub fn apply(&self, query: &DNSPacket, response: &mut DNSPacket, client: IpAddr) {
+        let Some(question) = query.questions.first() else { return };
+        let Some(rule) = self.find(question, client) else { return };
+
+        match &rule.action {
+            Action::Redirect { v4, v6 } => redirect(response, v4, v6),
+            Action::Cname(target) => {
+                let alias = Record { 
+                    name: question.name.clone(), 
+                    class: question.class, 
+                    ttl: rule.ttl.unwrap_or(self.ttl), 
+                    data: RData::Cname(target.clone()),
+                };
+
+                response.questions = query.questions.clone();
+                response.answers.insert(0, alias);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Time to live of records made up locally unless configured otherwise.
+pub const DEFAULT_TTL: u32 = 60;
+
+/// A response to `query` without any records.
+fn empty(query: &DNSPacket, rcode: u16) -> DNSPacket {
+    let mut response = query.reply();
+    response.set_rcode(rcode);
+    response
+}
+
+/// Replaces the addresses in the answer, keeping the owner and ttl of each replaced RRset.
+fn redirect(response: &mut DNSPacket, v4: &[Ipv4Addr], v6: &[Ipv6Addr]) {
+    let mut answers: Vec<Record> = Vec::with_capacity(response.answers.len());
+
+    for record in response.answers.drain(..) {
+        let replacements: Vec<RData> = match record.data {
+            RData::A(_) => v4.iter().map(|&a| RData::A(a)).collect(),
+            RData::Aaaa(_) => v6.iter().map(|&a| RData::Aaaa(a)).collect(),
+            _ => {
+                answers.push(record);
+                continue;
+            },
+        };
+
+        // the whole RRset is replaced when its first record is seen.
+        if answers.iter().any(|r| r.name == record.name && r.ty() == record.ty()) {
+            continue;
+        }
+
+        answers.extend(replacements.into_iter().map(|data| Record { 
+            name: record.name.clone(), 
+            class: record.class, 
+            ttl: record.ttl, 
+            data,
+        }));
+    }
+
+    // https records carry address hints too, which would bypass the redirect.
+    for record in answers.iter_mut() {
+        if let RData::Https(binding) = &mut record.data {
+            binding.redirect_hints(v4, v6);
+        }
+    }
+
+    response.answers = answers;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::MessageBuilder;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5));
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn query(s: &str, ty: u16) -> DNSPacket {
+        MessageBuilder::query(name(s), ty).build()
+    }
+
+    fn rules(lines: &[&str], order: RuleOrder) -> RuleSet {
+        RuleSet::new(lines.iter().map(|l| l.parse().unwrap()).collect(), order, DEFAULT_TTL)
+    }
+
+    fn record(owner: &str, ttl: u32, data: RData) -> Record {
+        Record { name: name(owner), class: record_class::IN, ttl, data }
+    }
+
+    #[test]
+    fn name_matches() {
+        let any: NameMatch = "any".parse().unwrap();
+        assert!(any.matches(&name("example.com")));
+        assert!(any.matches(&DomainName::root()));
+
+        let exact: NameMatch = "exact:example.com".parse().unwrap();
+        assert!(exact.matches(&name("Example.COM")));
+        assert!(!exact.matches(&name("www.example.com")));
+
+        let suffix: NameMatch = "suffix:example.com".parse().unwrap();
+        assert!(suffix.matches(&name("example.com")));
+        assert!(suffix.matches(&name("a.b.example.com")));
+        assert!(!suffix.matches(&name("notexample.com")));
+
+        let wildcard: NameMatch = "*.example.com".parse().unwrap();
+        assert!(!wildcard.matches(&name("example.com")));
+        assert!(wildcard.matches(&name("www.example.com")));
+
+        let regex: NameMatch = r"regex:^tracker[0-9]*\.".parse().unwrap();
+        assert!(regex.matches(&name("Tracker42.example.com")));
+        assert!(!regex.matches(&name("www.tracker1.example.com")));
+
+        assert!("exact".parse::<NameMatch>().is_err());
+        assert!("regex:(".parse::<NameMatch>().is_err());
+    }
+
+    #[test]
+    fn type_and_client_filters() {
+        let set = rules(&["any nxdomain type AAAA client 10.0.0.0/8"], RuleOrder::FirstMatch);
+
+        assert!(set.respond(&query("example.com", record_type::AAAA), CLIENT).is_some());
+        assert!(set.respond(&query("example.com", record_type::A), CLIENT).is_none());
+        assert!(set.respond(&query("example.com", record_type::AAAA), OTHER_CLIENT).is_none());
+
+        // ipv4 clients on a dual stack socket still belong to ipv4 networks.
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped());
+        assert!(set.respond(&query("example.com", record_type::AAAA), mapped).is_some());
+    }
+
+    #[test]
+    fn first_match_and_priority_order() {
+        let lines = ["suffix:example.com refused", "exact:www.example.com nxdomain priority 10"];
+        let www = query("www.example.com", record_type::A);
+
+        let first = rules(&lines, RuleOrder::FirstMatch).respond(&www, CLIENT).unwrap();
+        assert_eq!(first.rcode(), rcode::REFUSED);
+
+        let priority = rules(&lines, RuleOrder::Priority).respond(&www, CLIENT).unwrap();
+        assert_eq!(priority.rcode(), rcode::NXDOMAIN);
+
+        // ties go to the earlier rule.
+        let tied = ["any nodata priority 1", "any refused priority 1"];
+        let response = rules(&tied, RuleOrder::Priority).respond(&www, CLIENT).unwrap();
+        assert_eq!(response.rcode(), rcode::NOERROR);
+    }
+
+    #[test]
+    fn pass_forwards() {
+        let set = rules(&["exact:example.com pass", "any nxdomain"], RuleOrder::FirstMatch);
+        assert!(set.respond(&query("example.com", record_type::A), CLIENT).is_none());
+    }
+
+    #[test]
+    fn refusal_actions() {
+        let set = rules(&[
+            "exact:nx.example nxdomain", 
+            "exact:empty.example nodata", 
+            "exact:refused.example refused",
+        ], RuleOrder::FirstMatch);
+
+        for (owner, expected) in [
+            ("nx.example", rcode::NXDOMAIN), 
+            ("empty.example", rcode::NOERROR), 
+            ("refused.example", rcode::REFUSED),
+        ] {
+            let response = set.respond(&query(owner, record_type::A), CLIENT).unwrap();
+            assert_eq!(response.rcode(), expected);
+            assert!(response.answers.is_empty());
+            assert_eq!(response.questions[0].name, name(owner));
+        }
+    }
+
+    #[test]
+    fn redirect_synthesizes_addresses() {
+        let set = rules(&["any redirect 1.3.3.7,::1 ttl 30"], RuleOrder::FirstMatch);
+
+        let a = set.respond(&query("example.com", record_type::A), CLIENT).unwrap();
+        assert_eq!(a.answers, [record("example.com", 30, RData::A(Ipv4Addr::new(1, 3, 3, 7)))]);
+
+        let aaaa = set.respond(&query("example.com", record_type::AAAA), CLIENT).unwrap();
+        assert_eq!(aaaa.answers, [record("example.com", 30, RData::Aaaa(Ipv6Addr::LOCALHOST))]);
+
+        // only the upstream knows what else the name has.
+        assert!(set.respond(&query("example.com", record_type::HTTPS), CLIENT).is_none());
+    }
+
+    #[test]
+    fn redirect_rewrites_upstream_answers() {
+        let set = rules(&["any redirect 1.3.3.7"], RuleOrder::FirstMatch);
+        let q = query("example.com", record_type::A);
+
+        let mut response = q.reply();
+        response.answers = vec![
+            record("example.com", 300, RData::Cname(name("cdn.example.net"))),
+            record("cdn.example.net", 20, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            record("cdn.example.net", 20, RData::A(Ipv4Addr::new(192, 0, 2, 2))),
+        ];
+
+        set.apply(&q, &mut response, CLIENT);
+
+        assert_eq!(response.answers, [
+            record("example.com", 300, RData::Cname(name("cdn.example.net"))),
+            record("cdn.example.net", 20, RData::A(Ipv4Addr::new(1, 3, 3, 7))),
+        ]);
+    }
+
+    #[test]
+    fn redirect_replaces_https_hints() {
+        let set = rules(&["any redirect 1.3.3.7"], RuleOrder::FirstMatch);
+        let q = query("example.com", record_type::HTTPS);
+
+        let binding = ServiceBinding {
+            priority: 1,
+            target: DomainName::root(),
+            params: vec![
+                SvcParam::Alpn(vec![b"h2".to_vec()]),
+                SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                SvcParam::Ech(vec![1, 2, 3]),
+                SvcParam::Ipv6Hint(vec!["2001:db8::1".parse().unwrap()]),
+            ],
+        };
+
+        let mut response = q.reply();
+        response.answers = vec![record("example.com", 300, RData::Https(binding))];
+
+        set.apply(&q, &mut response, CLIENT);
+
+        let RData::Https(binding) = &response.answers[0].data else { panic!("not https") };
+        assert_eq!(binding.params, [
+            SvcParam::Alpn(vec![b"h2".to_vec()]),
+            SvcParam::Ipv4Hint(vec![Ipv4Addr::new(1, 3, 3, 7)]),
+        ]);
+    }
+
+    #[test]
+    fn cname_aliases_the_question() {
+        let set = rules(&["exact:intranet.example cname portal.corp.internal ttl 120"], 
+                        RuleOrder::FirstMatch);
+        let q = query("intranet.example", record_type::A);
+
+        // the target is resolved like any other name, not made up.
+        assert!(set.respond(&q, CLIENT).is_none());
+        assert_eq!(set.alias(&q, CLIENT), Some(&name("portal.corp.internal")));
+        assert_eq!(set.alias(&query("other.example", record_type::A), CLIENT), None);
+
+        let mut target_query = q.clone();
+        target_query.questions[0].name = name("portal.corp.internal");
+
+        let mut response = target_query.reply();
+        let address = RData::A(Ipv4Addr::new(10, 0, 0, 80));
+        response.answers = vec![record("portal.corp.internal", 60, address.clone())];
+
+        set.apply(&q, &mut response, CLIENT);
+
+        assert_eq!(response.questions, q.questions);
+        assert_eq!(response.answers, [
+            record("intranet.example", 120, RData::Cname(name("portal.corp.internal"))),
+            record("portal.corp.internal", 60, address),
+        ]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("any".parse::<Rule>().is_err());
+        assert!("any explode".parse::<Rule>().is_err());
+        assert!("any redirect nowhere".parse::<Rule>().is_err());
+        assert!("any nxdomain type BOGUS".parse::<Rule>().is_err());
+        assert!("any nxdomain client 10.0.0.0/33".parse::<Rule>().is_err());
+        assert!("any nxdomain priority".parse::<Rule>().is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Serving clients and asking upstreams over TCP.
+
+use crate::dns::*;
+use crate::pool::WorkerPool;
+use crate::proxy::{Precheck, Proxy, CLIENT_RESPONSE_TIMEOUT};
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a client connection may sit without sending a query before it's closed.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most client connections served at once, more are closed right away.
+const MAX_CONNECTIONS: usize = 128;
+
+/// Amount of threads resolving the queries of every connection.
+const WORKER_COUNT: usize = 32;
+
+/// Most queries of one connection resolved at once.
+const MAX_PIPELINED: usize = 16;
+
+/// Reads one message prefixed by its two byte length (RFC 1035 4.2.2).
+///
+/// Returns `None` if the peer closed the connection between messages. Timeouts are only
+/// reported as such before the first length byte, past it they fail with `UnexpectedEof`.
+pub fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 2];
+
+    // once a message started, giving up halfway loses the framing for good.
+    let cut_short = |e: io::Error| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "timed out in the middle of a message")
+        },
+        _ => e,
+    };
+
+    // a clean close only happens before the first length byte.
+    match stream.read(&mut length[..1])? {
+        0 => return Ok(None),
+        _ => stream.read_exact(&mut length[1..]).map_err(cut_short)?,
+    }
+
+    let mut message = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message).map_err(cut_short)?;
+
+    Ok(Some(message))
+}
+
+/// Writes one message prefixed by its two byte length in a single write.
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long for tcp"))?;
+
+    stream.write_all(&[&length.to_be_bytes()[..], message].concat())
+}
+
+/// Sends `query` to `upstream` over a fresh TCP connection and returns its response.
+pub fn exchange(upstream: impl ToSocketAddrs, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+    let address = upstream.to_socket_addrs()?
+                          .next()
+                          .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write_message(&mut stream, query)?;
+
+    read_message(&mut stream)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed connection"))
+}
+
+/// Accepts client connections forever, serving each one on its own thread up to
+/// `MAX_CONNECTIONS` at once. Their queries share one pool of workers.
+pub fn serve(listener: TcpListener, proxy: Arc<Proxy>) {
+    let pool = Arc::new(WorkerPool::new(WORKER_COUNT));
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => { eprintln!("Error: {}", e); continue }
+        };
+
+        if connections.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+            eprintln!("Error: too many tcp connections, closing one from {:?}", stream.peer_addr());
+            continue;
+        }
+
+        connections.fetch_add(1, Ordering::SeqCst);
+
+        let (proxy, pool, connections) =
+            (Arc::clone(&proxy), Arc::clone(&pool), Arc::clone(&connections));
+
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, proxy, &pool) {
+                eprintln!("Error: {}", e);
+            }
+
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// What the reader of a client connection shares with the workers answering it.
+struct Connection {
+    writer: Mutex<TcpStream>,
+    /// Queries still being resolved, the connection isn't idle while there are any.
+    in_flight: Mutex<usize>,
+    /// Signalled whenever a query was answered.
+    answered: Condvar,
+}
+
+impl Connection {
+    /// Writes a response, closing the connection if the client doesn't take it in time.
+    fn write(&self, response: &DNSPacket) {
+        let mut writer = self.writer.lock().unwrap();
+
+        if let Err(e) = write_message(&mut *writer, &response.serialize()) {
+            eprintln!("Error: closing tcp connection: {}", e);
+            // the reader wakes up to a closed socket too, which ends the connection.
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Marks a query as answered, letting the reader go on if it waited for room.
+    fn finish_query(&self) {
+        *self.in_flight.lock().unwrap() -= 1;
+        self.answered.notify_one();
+    }
+}
+
+/// Serves one client connection until it closes or idles out.
+///
+/// Queries are pipelined, each one is resolved on a worker of `pool` and its response is
+/// written as soon as it's ready, possibly out of order (RFC 7766 6.2.1.1). Once
+/// `MAX_PIPELINED` are in flight no more are read until one is answered.
+fn handle_connection(mut stream: TcpStream, proxy: Arc<Proxy>, pool: &WorkerPool)
+    -> io::Result<()> {
+    let client = stream.peer_addr()?.ip();
+
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    // a client that stops reading must not hold the workers writing to it.
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+
+    let connection = Arc::new(Connection {
+        writer: Mutex::new(stream.try_clone()?),
+        in_flight: Mutex::new(0),
+        answered: Condvar::new(),
+    });
+
+    loop {
+        // a client can't keep more than its share of the workers busy.
+        let in_flight = connection.in_flight.lock().unwrap();
+        drop(connection.answered.wait_while(in_flight, |n| *n >= MAX_PIPELINED).unwrap());
+
+        let bytes = match read_message(&mut stream) {
+            Ok(Some(b)) => b,
+            Ok(None) => break,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if *connection.in_flight.lock().unwrap() == 0 {
+                    break;
+                }
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+
+        // malformed queries and unknown EDNS versions are answered, if at all, right away.
+        let query = match proxy.precheck(&bytes, client) {
+            Precheck::Query(query) => query,
+            Precheck::Respond(response) => {
+                connection.write(&response);
+                continue;
+            },
+            Precheck::Ignore => continue,
+        };
+
+        *connection.in_flight.lock().unwrap() += 1;
+
+        let deadline = Instant::now() + CLIENT_RESPONSE_TIMEOUT;
+
+        let (proxy, connection) = (Arc::clone(&proxy), Arc::clone(&connection));
+
+        pool.execute(move || {
+            // local, blocked, rule answered and cached queries never reach the upstream.
+            let response = match proxy.local_response(&query, client) {
+                Some(response) => Ok(response),
+                None => proxy.resolve_tcp_until(&query, client, deadline),
+            };
+
+            // answers the upstreams failed to give may still be around stale.
+            let response = match response {
+                Ok(response) if response.rcode() != rcode::SERVFAIL => Ok(response),
+                failed => proxy.stale_response(&query, client).map(Ok).unwrap_or(failed),
+            };
+
+            // silence would only make the client wait out its own timeout.
+            let response = response.unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                query.server_failure()
+            });
+
+            connection.write(&response);
+            connection.finish_query();
+        });
+    }
+
+    Ok(())
+}
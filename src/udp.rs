@@ -0,0 +1,349 @@
+//! Serving clients over UDP.
+
+use crate::dns::*;
+use crate::pool::WorkerPool;
+use crate::proxy::{Precheck, Proxy, CLIENT_RESPONSE_TIMEOUT};
+use crate::upstream::{self, UpstreamPool};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Amount of threads finishing responses, which can block on TCP retries.
+const WORKER_COUNT: usize = 16;
+
+/// Most queries forwarded at once, half the id space so a free id is quick to find.
+const MAX_IN_FLIGHT: usize = 1 << 15;
+
+/// How often the in-flight table is checked for queries that timed out.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A query that was forwarded upstream and is waiting for its response.
+struct Pending {
+    /// The query as the client sent it.
+    query: DNSPacket,
+    client: SocketAddr,
+    /// The query as it's sent upstream.
+    upstream_query: Vec<u8>,
+    /// Questions of the upstream query, which an alias may have changed.
+    upstream_questions: Vec<Question>,
+    /// Pool the query is forwarded to.
+    upstreams: Arc<UpstreamPool>,
+    /// Index of the upstream currently asked.
+    upstream: usize,
+    /// Upstreams to fail over to, in order.
+    candidates: Vec<usize>,
+    sent: Instant,
+    /// When the client's query came in, before any failover.
+    received: Instant,
+    /// Whether the stale cache was asked once the client response timer ran out.
+    stale_checked: bool,
+    /// Whether the client already got a stale answer, the upstream's only updates the cache.
+    answered: bool,
+}
+
+/// Serves UDP clients concurrently through dedicated upstream sockets.
+///
+/// Forwarded queries get a fresh transaction id, responses are matched back to their
+/// client through the in-flight table keyed by that id.
+struct UdpServer {
+    /// Socket clients send their queries to.
+    socket: UdpSocket,
+    /// Sockets used only to talk to upstreams, one per address family.
+    upstream_v4: UdpSocket,
+    upstream_v6: Option<UdpSocket>,
+    proxy: Arc<Proxy>,
+    in_flight: Mutex<HashMap<u16, Pending>>,
+    pool: WorkerPool,
+}
+
+/// Serves UDP clients on `socket` forever.
+pub fn serve(socket: UdpSocket, proxy: Arc<Proxy>) -> io::Result<()> {
+    // hosts without ipv6 can still serve ipv4 upstreams.
+    let upstream_v6 = UdpSocket::bind("[::]:0").ok();
+
+    let server = Arc::new(UdpServer {
+        socket,
+        upstream_v4: UdpSocket::bind("0.0.0.0:0")?,
+        upstream_v6,
+        proxy,
+        in_flight: Mutex::new(HashMap::new()),
+        pool: WorkerPool::new(WORKER_COUNT),
+    });
+
+    let receiver = Arc::clone(&server);
+    thread::spawn(move || receiver.receive_responses(&receiver.upstream_v4));
+
+    if server.upstream_v6.is_some() {
+        let receiver = Arc::clone(&server);
+        thread::spawn(move || receiver.receive_responses(receiver.upstream_v6.as_ref().unwrap()));
+    }
+
+    let sweeper = Arc::clone(&server);
+    thread::spawn(move || sweeper.sweep());
+
+    server.receive_queries();
+    Ok(())
+}
+
+impl UdpServer {
+    /// Receives client queries and forwards them, never waiting on the upstream.
+    fn receive_queries(&self) {
+        // receive buffer, large enough for any EDNS payload size.
+        let mut buffer: Vec<u8> = vec![0; MAX_MESSAGE_SIZE];
+
+        loop {
+            // if receive fails retries.
+            let (length, src) = match self.socket.recv_from(&mut buffer) {
+                Ok((l, s)) => (l, s),  
+                Err(_) => continue,
+            };
+
+            // malformed queries and unknown EDNS versions are answered, if at all, right away.
+            let query = match self.proxy.precheck(&buffer[..length], src.ip()) {
+                Precheck::Query(query) => query,
+                Precheck::Respond(response) => {
+                    if let Err(e) = self.socket.send_to(&response.serialize(), src) {
+                        eprintln!("Error: {}", e);
+                    }
+                    continue;
+                },
+                Precheck::Ignore => continue,
+            };
+
+            // local, blocked, rule answered and cached queries never reach the upstream.
+            if let Some(response) = self.proxy.local_response(&query, src.ip()) {
+                self.send(response, &query, src);
+                continue;
+            }
+
+            self.forward(query, src);
+        }
+    }
+
+    /// Sends a client's query upstream under an id that isn't in flight yet.
+    fn forward(&self, query: DNSPacket, client: SocketAddr) {
+        let mut upstream_query = self.proxy.upstream_query(&query, client.ip());
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        // every id taken would leave nothing to pick, fail instead of waiting for one.
+        if in_flight.len() >= MAX_IN_FLIGHT {
+            drop(in_flight);
+            eprintln!("Error: too many queries in flight, failing query from {}", client);
+            self.send(query.server_failure(), &query, client);
+            return;
+        }
+
+        let id = std::iter::repeat_with(|| upstream::random() as u16)
+            .find(|id| !in_flight.contains_key(id))
+            .unwrap();
+
+        upstream_query.header.id = id;
+
+        let upstreams = Arc::clone(self.proxy.upstreams_for(&upstream_query));
+
+        let mut pending = Pending {
+            query,
+            client,
+            upstream_query: upstream_query.serialize(),
+            upstream_questions: upstream_query.questions,
+            candidates: upstreams.candidates(),
+            upstreams,
+            upstream: 0,
+            sent: Instant::now(),
+            received: Instant::now(),
+            stale_checked: false,
+            answered: false,
+        };
+
+        // registered while still holding the lock so a quick response can't miss it.
+        if self.send_to_next(&mut pending) {
+            in_flight.insert(id, pending);
+        } else {
+            self.give_up(&pending);
+        }
+    }
+
+    /// Sends the query to the next candidate that accepts it.
+    ///
+    /// Returns false once every candidate has been tried.
+    fn send_to_next(&self, pending: &mut Pending) -> bool {
+        while !pending.candidates.is_empty() {
+            let index = pending.candidates.remove(0);
+            let address = pending.upstreams.get(index).address;
+
+            let socket = match address {
+                SocketAddr::V4(_) => Some(&self.upstream_v4),
+                SocketAddr::V6(_) => self.upstream_v6.as_ref(),
+            };
+
+            let sent = socket.ok_or(io::ErrorKind::Unsupported.into())
+                             .and_then(|s| s.send_to(&pending.upstream_query, address));
+
+            match sent {
+                Ok(_) => {
+                    pending.upstream = index;
+                    pending.sent = Instant::now();
+                    return true;
+                },
+                Err(e) => {
+                    eprintln!("Error: sending to {} failed: {}", address, e);
+                    pending.upstreams.report_failure(index);
+                },
+            }
+        }
+
+        false
+    }
+
+    /// Answers a query no upstream answered from the stale cache, or with SERVFAIL if it
+    /// can't, unless the client got its stale answer already.
+    fn give_up(&self, pending: &Pending) {
+        if pending.answered {
+            return;
+        }
+
+        let response = self.proxy
+            .stale_response(&pending.query, pending.client.ip())
+            .unwrap_or_else(|| pending.query.server_failure());
+
+        self.send(response, &pending.query, pending.client);
+    }
+
+    /// Answers a query the upstreams are slow to answer from the stale cache, once.
+    fn answer_stale(&self, pending: &mut Pending) {
+        pending.stale_checked = true;
+
+        if let Some(response) = self.proxy.stale_response(&pending.query, pending.client.ip()) {
+            self.send(response, &pending.query, pending.client);
+            pending.answered = true;
+        }
+    }
+
+    /// Sends the response to `query` to `client`.
+    ///
+    /// The client can't receive more than it advertised, truncated responses let it retry
+    /// over tcp.
+    fn send(&self, mut response: DNSPacket, query: &DNSPacket, client: SocketAddr) {
+        response.truncate(query.max_udp_size());
+
+        if let Err(e) = self.socket.send_to(&response.serialize(), client) {
+            eprintln!("Error: {}", e);
+        }
+    }
+
+    /// Receives upstream responses and hands them to the workers along with their query.
+    fn receive_responses(self: &Arc<Self>, socket: &UdpSocket) {
+        let mut buffer: Vec<u8> = vec![0; MAX_MESSAGE_SIZE];
+
+        loop {
+            let (length, src) = match socket.recv_from(&mut buffer) {
+                Ok((l, s)) => (l, s),
+                Err(_) => continue,
+            };
+
+            match PacketParser::new(&buffer[..length]).deserialize() {
+                Ok(response) => self.dispatch(response, src),
+                Err(e) => eprintln!("Error: malformed response from {}: {}", src, 
+                                    e.with_context(&buffer[..length])),
+            }
+        }
+    }
+
+    /// Matches a response to its pending query and finishes it on a worker.
+    fn dispatch(self: &Arc<Self>, response: DNSPacket, src: SocketAddr) {
+        let pending = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            match in_flight.get(&response.header.id) {
+                // a response from elsewhere or for some other question is spoofed or stale.
+                Some(p) if p.upstreams.get(p.upstream).address == src 
+                    && p.upstream_questions == response.questions => {
+                    in_flight.remove(&response.header.id).unwrap()
+                },
+                _ => return,
+            }
+        };
+
+        pending.upstreams.report_success(pending.upstream, pending.sent.elapsed());
+
+        let server = Arc::clone(self);
+        self.pool.execute(move || server.finish(pending, response));
+    }
+
+    /// Fails queries over to their next upstream once the current one times out, and
+    /// answers clients that waited too long from stale meanwhile.
+    fn sweep(&self) {
+        loop {
+            thread::sleep(SWEEP_INTERVAL);
+
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            for pending in in_flight.values_mut() {
+                if !pending.stale_checked && pending.received.elapsed() >= CLIENT_RESPONSE_TIMEOUT {
+                    self.answer_stale(pending);
+                }
+            }
+
+            let expired: Vec<u16> = in_flight
+                .iter()
+                .filter(|(_, p)| p.sent.elapsed() >= p.upstreams.get(p.upstream).timeout)
+                .map(|(&id, _)| id)
+                .collect();
+
+            for id in expired {
+                let mut pending = in_flight.remove(&id).unwrap();
+                pending.upstreams.report_failure(pending.upstream);
+
+                if self.send_to_next(&mut pending) {
+                    in_flight.insert(id, pending);
+                } else {
+                    self.give_up(&pending);
+                }
+            }
+        }
+    }
+
+    /// Applies the proxy policy to a response and sends it to the client.
+    fn finish(&self, pending: Pending, mut response: DNSPacket) {
+        let Pending { query, client, received, answered, .. } = pending;
+
+        // the upstream couldn't fit everything in a datagram, ask it again over tcp, still
+        // within the time the client waits before it's answered from stale.
+        let full_response = match response.header.tc {
+            1 => {
+                let deadline = received + CLIENT_RESPONSE_TIMEOUT;
+                let retried = match answered {
+                    true => self.proxy.resolve_tcp(&query, client.ip()),
+                    false => self.proxy.resolve_tcp_until(&query, client.ip(), deadline),
+                };
+
+                retried.map_err(|e| eprintln!("Error: {}", e)).ok()
+            },
+            _ => None,
+        };
+
+        // if the retry failed, relay the truncated response as is.
+        let mut response = full_response.unwrap_or_else(|| {
+            self.proxy.client_response(&query, &mut response, client.ip());
+            response
+        });
+
+        // the client made do with a stale answer, this one only refreshed the cache.
+        if answered {
+            return;
+        }
+
+        // a failing upstream is no better than no upstream.
+        if response.rcode() == rcode::SERVFAIL {
+            if let Some(stale) = self.proxy.stale_response(&query, client.ip()) {
+                response = stale;
+            }
+        }
+
+        self.send(response, &query, client);
+    }
+}
@@ -0,0 +1,241 @@
+//! Upstream resolvers, their health and how one is picked.
+
+use crate::dns::*;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Used when the configuration doesn't name any upstream.
+pub const DEFAULT_UPSTREAM: &str = "8.8.8.8:53";
+
+/// How long to wait on an upstream that has no timeout of its own.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Consecutive failures after which an upstream is ejected.
+const MAX_FAILURES: u32 = 3;
+
+/// How often ejected upstreams are probed for readmission.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Weight of a new sample in the smoothed round trip time, out of 8.
+const RTT_WEIGHT: u32 = 2;
+
+/// Random number from the standard library's hasher seeds, good enough for ids and shuffling.
+pub fn random() -> u64 {
+    // every `RandomState` is seeded differently, hashing nothing is enough.
+    RandomState::new().build_hasher().finish()
+}
+
+/// How the upstream for a query is picked among the healthy ones.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strategy {
+    /// Always the first in configured order, the next ones are only used when it fails.
+    #[default]
+    Failover,
+    /// Each query starts at the upstream after the one the previous query started at.
+    RoundRobin,
+    /// Lowest smoothed round trip time first.
+    Fastest,
+    /// A random order for every query.
+    Random,
+}
+
+/// An upstream as it appears in the configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamConfig {
+    /// Where queries are sent to, over UDP and TCP alike.
+    pub address: SocketAddr,
+    /// How long to wait for a response before asking the next upstream.
+    pub timeout: Duration,
+}
+
+/// Queries for names at or below `suffix` go to `upstreams` instead of the default ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardRule {
+    /// Names at or below this one are forwarded.
+    pub suffix: DomainName,
+    /// Where they're forwarded to.
+    pub upstreams: Vec<UpstreamConfig>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    /// Smoothed round trip time, `None` until the first response.
+    rtt: Option<Duration>,
+    /// Failures since the last success.
+    failures: u32,
+}
+
+/// An upstream along with how well it has been answering.
+pub struct Upstream {
+    /// Where queries are sent to.
+    pub address: SocketAddr,
+    /// How long to wait for a response.
+    pub timeout: Duration,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    fn new(config: &UpstreamConfig) -> Self {
+        Self { address: config.address, timeout: config.timeout, health: Mutex::default() }
+    }
+
+    /// Ejected upstreams only get queries when every upstream is ejected.
+    pub fn is_ejected(&self) -> bool {
+        self.health.lock().unwrap().failures >= MAX_FAILURES
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.health.lock().unwrap().rtt
+    }
+}
+
+/// The configured upstreams, along with their health.
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    /// Where the next round robin selection starts.
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    /// Creates a pool of healthy upstreams picked by `strategy`, `DEFAULT_UPSTREAM` if
+    /// `configs` is empty.
+    pub fn new(configs: &[UpstreamConfig], strategy: Strategy) -> Self {
+        let default = [UpstreamConfig { 
+            address: DEFAULT_UPSTREAM.parse().unwrap(), 
+            timeout: DEFAULT_TIMEOUT,
+        }];
+
+        let configs = if configs.is_empty() { &default[..] } else { configs };
+
+        Self { 
+            upstreams: configs.iter().map(Upstream::new).collect(), 
+            strategy, 
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The upstream at `index`, as returned by `candidates`.
+    pub fn get(&self, index: usize) -> &Upstream {
+        &self.upstreams[index]
+    }
+
+    /// Indices of the upstreams to try for a query, in order.
+    ///
+    /// Ejected upstreams come last, so a query still goes somewhere when all of them are down.
+    pub fn candidates(&self) -> Vec<usize> {
+        let count = self.upstreams.len();
+        let mut order: Vec<usize> = (0..count).collect();
+
+        match self.strategy {
+            Strategy::Failover => {},
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+                order.rotate_left(start);
+            },
+            Strategy::Fastest => {
+                // unmeasured upstreams go first so they get measured.
+                order.sort_by_key(|&i| self.upstreams[i].rtt().unwrap_or_default());
+            },
+            Strategy::Random => {
+                for i in (1..count).rev() {
+                    order.swap(i, random() as usize % (i + 1));
+                }
+            },
+        }
+
+        // stable, so the strategy's order is kept within healthy and ejected upstreams.
+        order.sort_by_key(|&i| self.upstreams[i].is_ejected());
+        order
+    }
+
+    /// Records a response from the upstream at `index` that took `rtt`.
+    pub fn report_success(&self, index: usize, rtt: Duration) {
+        let mut health = self.upstreams[index].health.lock().unwrap();
+
+        if health.failures >= MAX_FAILURES {
+            eprintln!("Info: upstream {} readmitted", self.upstreams[index].address);
+        }
+
+        health.failures = 0;
+        health.rtt = Some(match health.rtt {
+            Some(old) => (old * (8 - RTT_WEIGHT) + rtt * RTT_WEIGHT) / 8,
+            None => rtt,
+        });
+    }
+
+    /// Records a timeout or error from the upstream at `index`.
+    pub fn report_failure(&self, index: usize) {
+        let mut health = self.upstreams[index].health.lock().unwrap();
+        health.failures += 1;
+
+        if health.failures == MAX_FAILURES {
+            eprintln!("Info: upstream {} ejected", self.upstreams[index].address);
+        }
+    }
+
+    /// Probes ejected upstreams forever, readmitting the ones that answer.
+    pub fn check_health(self: Arc<Self>) {
+        loop {
+            thread::sleep(PROBE_INTERVAL);
+
+            for (index, upstream) in self.upstreams.iter().enumerate() {
+                if !upstream.is_ejected() {
+                    continue;
+                }
+
+                let sent = Instant::now();
+
+                match probe(upstream) {
+                    Ok(()) => self.report_success(index, sent.elapsed()),
+                    Err(e) => eprintln!("Error: probing {} failed: {}", upstream.address, e),
+                }
+            }
+        }
+    }
+}
+
+/// Asks an upstream for the root NS records and waits for any answer.
+fn probe(upstream: &Upstream) -> Result<(), String> {
+    let mut query = DNSPacket::default();
+    query.header.id = random() as u16;
+    query.questions.push(Question { 
+        name: DomainName::root(), 
+        ty: record_type::NS, 
+        class: record_class::IN,
+    });
+
+    let bind_address = match upstream.address {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+
+    let socket = UdpSocket::bind(bind_address).map_err(|e| e.to_string())?;
+    socket.set_read_timeout(Some(upstream.timeout)).map_err(|e| e.to_string())?;
+    socket.connect(upstream.address).map_err(|e| e.to_string())?;
+    socket.send(&query.serialize()).map_err(|e| e.to_string())?;
+
+    let mut buffer: Vec<u8> = vec![0; MAX_MESSAGE_SIZE];
+    let deadline = Instant::now() + upstream.timeout;
+
+    while Instant::now() < deadline {
+        let length = socket.recv(&mut buffer).map_err(|e| e.to_string())?;
+
+        let message = &buffer[..length];
+        let response = PacketParser::new(message)
+            .deserialize()
+            .map_err(|e| e.with_context(message))?;
+
+        if response.header.id == query.header.id {
+            return Ok(());
+        }
+    }
+
+    Err("timed out".to_string())
+}
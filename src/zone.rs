@@ -0,0 +1,588 @@
+//! Zones we answer authoritatively for.
+
+use crate::dns::*;
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// Longest chain of aliases followed within a zone.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Records of a domain we answer for ourselves instead of forwarding.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    /// Name at the top of the zone.
+    pub origin: DomainName,
+    /// Every record of the zone by owner name, including the SOA and NS records at the origin.
+    records: HashMap<DomainName, Vec<Record>>,
+    /// Every owner name and the names between it and the origin, which exist too.
+    names: HashSet<DomainName>,
+}
+
+impl Zone {
+    /// Creates a zone with just a placeholder SOA and NS record, like the local zones of
+    /// other resolvers. Negative answers are cached for `ttl` seconds.
+    pub fn new(origin: DomainName, ttl: u32) -> Self {
+        let localhost: DomainName = "localhost".parse().unwrap();
+        let mut zone = Self::empty(origin.clone());
+
+        zone.insert(Record {
+            name: origin.clone(),
+            class: record_class::IN,
+            ttl,
+            data: RData::Soa {
+                mname: localhost.clone(),
+                rname: "nobody.invalid".parse().unwrap(),
+                serial: 1,
+                refresh: 3600,
+                retry: 1200,
+                expire: 604800,
+                minimum: ttl,
+            },
+        });
+        zone.insert(Record { name: origin, class: record_class::IN, ttl, data: RData::Ns(localhost) });
+
+        zone
+    }
+
+    /// Creates a zone from the records of a master file, which must have a SOA record at
+    /// `origin` and nothing outside of it.
+    pub fn from_records(origin: DomainName, records: Vec<Record>) -> Result<Self, String> {
+        let mut zone = Self::empty(origin);
+
+        for record in records {
+            if !record.name.is_subdomain_of(&zone.origin) {
+                return Err(format!("record for {} is outside of zone {}.", record.name, zone.origin));
+            }
+
+            zone.insert(record);
+        }
+
+        if zone.rrset(&zone.origin, record_type::SOA).count() != 1 {
+            return Err(format!("zone {} needs exactly one SOA record at its origin.", zone.origin));
+        }
+
+        Ok(zone)
+    }
+
+    fn empty(origin: DomainName) -> Self {
+        Self { origin, records: HashMap::new(), names: HashSet::new() }
+    }
+
+    /// Adds a record unless the zone already has it.
+    pub fn insert(&mut self, record: Record) {
+        let mut name = Some(record.name.clone());
+
+        // once a name is known, so are the ones above it.
+        while let Some(n) = name.filter(|n| n.is_subdomain_of(&self.origin)) {
+            if !self.names.insert(n.clone()) {
+                break;
+            }
+            name = n.parent();
+        }
+
+        let records = self.records.entry(record.name.clone()).or_default();
+
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+
+    /// Records of type `ty` owned by `name`.
+    fn rrset(&self, name: &DomainName, ty: u16) -> impl Iterator<Item = &Record> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |r| r.ty() == ty)
+    }
+
+    /// Whether `name` owns records or has names with records below it.
+    fn exists(&self, name: &DomainName) -> bool {
+        self.names.contains(name)
+    }
+
+    /// The highest delegation point below the origin at or above `name`, if any.
+    fn delegation(&self, name: &DomainName) -> Option<DomainName> {
+        let mut cut = None;
+        let mut current = name.clone();
+
+        while current != self.origin {
+            if self.rrset(&current, record_type::NS).next().is_some() {
+                cut = Some(current.clone());
+            }
+
+            current = current.parent()?;
+        }
+
+        cut
+    }
+
+    /// Records owned by `name`, or synthesized from the wildcard at its closest encloser
+    /// (RFC 4592). `None` if the name doesn't exist.
+    fn lookup(&self, name: &DomainName) -> Option<Vec<Record>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records.clone());
+        }
+
+        // empty non-terminals exist, but have no records.
+        if self.exists(name) {
+            return Some(Vec::new());
+        }
+
+        let mut encloser = name.parent()?;
+
+        while !self.exists(&encloser) {
+            encloser = encloser.parent()?;
+        }
+
+        let wildcard = "*".parse::<DomainName>().ok()?.concat(&encloser).ok()?;
+
+        self.records.get(&wildcard).map(|records| {
+            records.iter()
+                   .map(|r| Record { name: name.clone(), ..r.clone() })
+                   .collect()
+        })
+    }
+
+    /// Answers the first question of `query`, which must lie within the zone.
+    ///
+    /// Aliases are followed as long as they stay within the zone, names below a delegation
+    /// get a referral with glue. Negative answers carry the SOA and NS records of the zone,
+    /// with NXDOMAIN for names that don't exist and NOERROR for names without records of the
+    /// asked type.
+    pub fn answer(&self, query: &DNSPacket) -> DNSPacket {
+        let mut response = query.reply();
+        let Some(question) = query.questions.first() else { return response };
+        let mut name = question.name.clone();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            // aliases leaving the zone are left to the client to follow.
+            if !name.is_subdomain_of(&self.origin) {
+                break;
+            }
+
+            if let Some(cut) = self.delegation(&name) {
+                self.add_referral(&mut response, &cut);
+                break;
+            }
+
+            let Some(records) = self.lookup(&name) else {
+                response.set_rcode(rcode::NXDOMAIN);
+                self.add_negative_authority(&mut response);
+                break;
+            };
+
+            let answers: Vec<Record> = records
+                .iter()
+                .filter(|r| question.ty == record_type::ANY || r.ty() == question.ty)
+                .cloned()
+                .collect();
+
+            if !answers.is_empty() {
+                response.answers.extend(answers);
+                break;
+            }
+
+            // an alias stands in for records of every other type.
+            let alias = records.into_iter().find_map(|r| match &r.data {
+                RData::Cname(target) => Some((target.clone(), r)),
+                _ => None,
+            });
+
+            match alias {
+                Some((target, alias)) => {
+                    name = target;
+                    response.answers.push(alias);
+                },
+                None => {
+                    self.add_negative_authority(&mut response);
+                    break;
+                },
+            }
+        }
+
+        response
+    }
+
+    /// Adds the NS records of the delegation at `cut` and the addresses of the name servers
+    /// within the zone. Only the data before the referral, if any, is authoritative.
+    fn add_referral(&self, response: &mut DNSPacket, cut: &DomainName) {
+        response.header.aa = !response.answers.is_empty() as u8;
+
+        for ns in self.rrset(cut, record_type::NS) {
+            let RData::Ns(server) = &ns.data else { continue };
+
+            let glue = self.rrset(server, record_type::A).chain(self.rrset(server, record_type::AAAA));
+            response.additionals.extend(glue.cloned());
+            response.authorities.push(ns.clone());
+        }
+    }
+
+    /// Adds the SOA, with its ttl capped at its minimum field (RFC 2308 3), and NS records.
+    fn add_negative_authority(&self, response: &mut DNSPacket) {
+        for record in self.rrset(&self.origin, record_type::SOA) {
+            let mut soa = record.clone();
+
+            if let RData::Soa { minimum, .. } = soa.data {
+                soa.ttl = soa.ttl.min(minimum);
+            }
+
+            response.authorities.push(soa);
+        }
+
+        response.authorities.extend(self.rrset(&self.origin, record_type::NS).cloned());
+    }
+}
+
+/// Every zone we answer for, along with the host entries outside of them.
+#[derive(Debug, Default)]
+pub struct LocalZones {
+    zones: Vec<Zone>,
+    /// Records of host entries outside of every zone, by owner name. Only these exact names
+    /// and types are answered, anything else about them is forwarded.
+    hosts: HashMap<DomainName, Vec<Record>>,
+    /// Time to live of the records added through host entries.
+    ttl: u32,
+}
+
+impl LocalZones {
+    /// Creates an empty set of zones, host entries get records with `ttl`.
+    pub fn new(ttl: u32) -> Self {
+        Self { zones: Vec::new(), hosts: HashMap::new(), ttl }
+    }
+
+    /// The most specific zone `name` lies within.
+    fn find(&self, name: &DomainName) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|z| name.is_subdomain_of(&z.origin))
+            .max_by_key(|z| z.origin.label_count())
+    }
+
+    /// Adds a zone read from a master file, replacing any zone with the same origin.
+    pub fn add(&mut self, zone: Zone) {
+        self.zones.retain(|z| z.origin != zone.origin);
+        self.zones.push(zone);
+    }
+
+    /// Adds an empty zone at `origin`, unless there's one already.
+    pub fn add_zone(&mut self, origin: DomainName) {
+        if !self.zones.iter().any(|z| z.origin == origin) {
+            self.zones.push(Zone::new(origin, self.ttl));
+        }
+    }
+
+    /// Adds a record to the most specific zone it lies within, records outside of every zone
+    /// are kept apart so they don't shadow the rest of their domain.
+    fn insert(&mut self, record: Record) {
+        let index = self.zones
+            .iter()
+            .enumerate()
+            .filter(|(_, z)| record.name.is_subdomain_of(&z.origin))
+            .max_by_key(|(_, z)| z.origin.label_count())
+            .map(|(i, _)| i);
+
+        match index {
+            Some(index) => self.zones[index].insert(record),
+            None => {
+                let records = self.hosts.entry(record.name.clone()).or_default();
+
+                if !records.contains(&record) {
+                    records.push(record);
+                }
+            },
+        }
+    }
+
+    /// Adds an address record for `name` and the matching PTR record.
+    pub fn add_host(&mut self, address: IpAddr, name: &DomainName) {
+        let data = match address {
+            IpAddr::V4(a) => RData::A(a),
+            IpAddr::V6(a) => RData::Aaaa(a),
+        };
+
+        self.insert(Record { name: name.clone(), class: record_class::IN, ttl: self.ttl, data });
+        self.insert(Record {
+            name: DomainName::reverse(address),
+            class: record_class::IN,
+            ttl: self.ttl,
+            data: RData::Ptr(name.clone()),
+        });
+    }
+
+    /// Reads a hosts file of `<address> <name>...` lines, returning how many names were added.
+    pub fn load_hosts(&mut self, path: &str) -> Result<usize, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read hosts file {}: {}", path, e))?;
+
+        let mut count = 0;
+
+        for line in text.lines() {
+            let mut words = line.split('#').next().unwrap_or_default().split_whitespace();
+
+            let Some(address) = words.next() else { continue };
+            let address = address.parse()
+                .map_err(|_| format!("invalid address `{}` in {}", address, path))?;
+
+            for name in words {
+                self.add_host(address, &name.parse()?);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Answers `query` if its first question lies within one of the zones, or asks for the
+    /// name and type of a host entry outside of them.
+    pub fn answer(&self, query: &DNSPacket) -> Option<DNSPacket> {
+        let question = query.questions.first()?;
+
+        if let Some(zone) = self.find(&question.name) {
+            return Some(zone.answer(query));
+        }
+
+        let answers: Vec<Record> = self.hosts
+            .get(&question.name)?
+            .iter()
+            .filter(|r| r.ty() == question.ty)
+            .cloned()
+            .collect();
+
+        if answers.is_empty() {
+            return None;
+        }
+
+        let mut response = query.reply();
+        response.answers = answers;
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::MessageBuilder;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn query(s: &str, ty: u16) -> DNSPacket {
+        MessageBuilder::query(name(s), ty).build()
+    }
+
+    fn record(owner: &str, data: RData) -> Record {
+        Record { name: name(owner), class: record_class::IN, ttl: 3600, data }
+    }
+
+    fn a(address: [u8; 4]) -> RData {
+        RData::A(Ipv4Addr::from(address))
+    }
+
+    fn cname(target: &str) -> RData {
+        RData::Cname(name(target))
+    }
+
+    fn zone() -> Zone {
+        let soa = RData::Soa {
+            mname: name("ns.example.com"),
+            rname: name("hostmaster.example.com"),
+            serial: 1,
+            refresh: 3600,
+            retry: 900,
+            expire: 604800,
+            minimum: 300,
+        };
+
+        Zone::from_records(name("example.com"), vec![
+            record("example.com", soa),
+            record("example.com", RData::Ns(name("ns.example.com"))),
+            record("ns.example.com", a([192, 0, 2, 53])),
+            record("www.example.com", a([192, 0, 2, 1])),
+            record("c.b.deep.example.com", a([192, 0, 2, 3])),
+            record("*.wild.example.com", a([192, 0, 2, 2])),
+            record("sub.wild.example.com", RData::Txt(vec![b"no wildcard below".to_vec()])),
+            record("alias.example.com", cname("www.example.com")),
+            record("alias2.example.com", cname("alias.example.com")),
+            record("out.example.com", cname("elsewhere.example.net")),
+            record("loop1.example.com", cname("loop2.example.com")),
+            record("loop2.example.com", cname("loop1.example.com")),
+            record("opaque.example.com", RData::Unknown(record_type::CNAME, vec![0])),
+            record("sub.example.com", RData::Ns(name("ns.sub.example.com"))),
+            record("sub.example.com", RData::Ns(name("ns.example.net"))),
+            record("ns.sub.example.com", a([192, 0, 2, 54])),
+            record("tosub.example.com", cname("host.sub.example.com")),
+        ]).unwrap()
+    }
+
+    /// Types of the records in a section, in order.
+    fn types(records: &[Record]) -> Vec<u16> {
+        records.iter().map(|r| r.ty()).collect()
+    }
+
+    #[test]
+    fn negative_answers() {
+        let zone = zone();
+
+        let response = zone.answer(&query("missing.example.com", record_type::A));
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+        assert_eq!(response.header.aa, 1);
+        assert!(response.answers.is_empty());
+        assert_eq!(types(&response.authorities), [record_type::SOA, record_type::NS]);
+        // capped at the SOA minimum (RFC 2308 3).
+        assert_eq!(response.authorities[0].ttl, 300);
+
+        let response = zone.answer(&query("www.example.com", record_type::MX));
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert_eq!(types(&response.authorities), [record_type::SOA, record_type::NS]);
+
+        // names with records below them exist without any of their own.
+        let response = zone.answer(&query("b.deep.example.com", record_type::A));
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert!(response.answers.is_empty());
+
+        let response = zone.answer(&query("www.example.com", record_type::ANY));
+        assert_eq!(types(&response.answers), [record_type::A]);
+    }
+
+    #[test]
+    fn wildcards() {
+        let zone = zone();
+
+        let response = zone.answer(&query("host.wild.example.com", record_type::A));
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert_eq!(response.answers, [record("host.wild.example.com", a([192, 0, 2, 2]))]);
+
+        let response = zone.answer(&query("a.b.wild.example.com", record_type::A));
+        assert_eq!(response.answers[0].name, name("a.b.wild.example.com"));
+
+        let response = zone.answer(&query("host.wild.example.com", record_type::AAAA));
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert!(response.answers.is_empty());
+
+        // existing names block the wildcard, for themselves and below them (RFC 4592 2.2.2).
+        let response = zone.answer(&query("sub.wild.example.com", record_type::A));
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert!(response.answers.is_empty());
+
+        let response = zone.answer(&query("a.sub.wild.example.com", record_type::A));
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+    }
+
+    #[test]
+    fn alias_chains() {
+        let zone = zone();
+
+        let response = zone.answer(&query("alias2.example.com", record_type::A));
+        assert_eq!(response.answers, [
+            record("alias2.example.com", cname("alias.example.com")),
+            record("alias.example.com", cname("www.example.com")),
+            record("www.example.com", a([192, 0, 2, 1])),
+        ]);
+
+        // the alias itself is what's asked for.
+        let response = zone.answer(&query("alias2.example.com", record_type::CNAME));
+        assert_eq!(response.answers, [record("alias2.example.com", cname("alias.example.com"))]);
+
+        // leaving the zone, the client follows the rest.
+        let response = zone.answer(&query("out.example.com", record_type::A));
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert_eq!(types(&response.answers), [record_type::CNAME]);
+        assert!(response.authorities.is_empty());
+
+        let response = zone.answer(&query("loop1.example.com", record_type::A));
+        assert_eq!(response.answers.len(), MAX_CNAME_CHAIN);
+
+        // an alias in its generic form isn't followed.
+        let response = zone.answer(&query("opaque.example.com", record_type::A));
+        assert!(response.answers.is_empty());
+        assert_eq!(types(&response.authorities), [record_type::SOA, record_type::NS]);
+    }
+
+    #[test]
+    fn referrals() {
+        let zone = zone();
+
+        for ty in [record_type::A, record_type::NS] {
+            let response = zone.answer(&query("host.sub.example.com", ty));
+
+            assert_eq!(response.rcode(), rcode::NOERROR);
+            assert_eq!(response.header.aa, 0);
+            assert!(response.answers.is_empty());
+            assert_eq!(types(&response.authorities), [record_type::NS, record_type::NS]);
+            // only name servers within the zone have glue.
+            assert_eq!(response.additionals, [record("ns.sub.example.com", a([192, 0, 2, 54]))]);
+        }
+
+        // an alias into the delegation is ours, the rest is the referral.
+        let response = zone.answer(&query("tosub.example.com", record_type::A));
+        assert_eq!(response.header.aa, 1);
+        assert_eq!(types(&response.answers), [record_type::CNAME]);
+        assert_eq!(types(&response.authorities), [record_type::NS, record_type::NS]);
+    }
+
+    #[test]
+    fn zones_need_a_soa_and_nothing_outside() {
+        let records = vec![record("www.example.com", a([192, 0, 2, 1]))];
+        assert!(Zone::from_records(name("example.com"), records).is_err());
+
+        let mut records = zone().records.into_values().flatten().collect::<Vec<Record>>();
+        records.push(record("www.example.net", a([192, 0, 2, 1])));
+        assert!(Zone::from_records(name("example.com"), records).is_err());
+    }
+
+    #[test]
+    fn host_entries() {
+        let mut zones = LocalZones::new(60);
+        let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 10));
+        let v6 = IpAddr::V6("fd00::10".parse::<Ipv6Addr>().unwrap());
+
+        zones.add_zone(name("lab.internal"));
+        zones.add_zone(name("10.in-addr.arpa"));
+        zones.add_host(address, &name("nas.lab.internal"));
+        zones.add_host(v6, &name("nas.lab.internal"));
+
+        let response = zones.answer(&query("nas.lab.internal", record_type::A)).unwrap();
+        assert_eq!(response.answers[0].data, RData::A(Ipv4Addr::new(10, 0, 0, 10)));
+        assert_eq!(response.answers[0].ttl, 60);
+
+        // the reverse names are generated, in the reverse zone or on their own.
+        let reverse = DomainName::reverse(address).to_string();
+        let response = zones.answer(&query(&reverse, record_type::PTR)).unwrap();
+        assert_eq!(response.answers[0].data, RData::Ptr(name("nas.lab.internal")));
+
+        let reverse = DomainName::reverse(v6).to_string();
+        let response = zones.answer(&query(&reverse, record_type::PTR)).unwrap();
+        assert_eq!(response.answers[0].data, RData::Ptr(name("nas.lab.internal")));
+
+        // other names of a local zone don't exist.
+        let response = zones.answer(&query("printer.lab.internal", record_type::A)).unwrap();
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+        let response = zones.answer(&query("11.0.0.10.in-addr.arpa", record_type::PTR)).unwrap();
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+    }
+
+    #[test]
+    fn host_entries_outside_zones() {
+        let mut zones = LocalZones::new(60);
+        zones.add_host(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 10)), &name("nas.home"));
+
+        let response = zones.answer(&query("NAS.home", record_type::A)).unwrap();
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+
+        let response = zones.answer(&query("10.0.0.10.in-addr.arpa", record_type::PTR)).unwrap();
+        assert_eq!(response.answers[0].data, RData::Ptr(name("nas.home")));
+
+        // everything else is forwarded.
+        assert!(zones.answer(&query("nas.home", record_type::AAAA)).is_none());
+        assert!(zones.answer(&query("nas.home", record_type::MX)).is_none());
+        assert!(zones.answer(&query("www.nas.home", record_type::A)).is_none());
+        assert!(zones.answer(&query("home", record_type::A)).is_none());
+        assert!(zones.answer(&query("11.0.0.10.in-addr.arpa", record_type::PTR)).is_none());
+    }
+}